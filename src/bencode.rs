@@ -0,0 +1,493 @@
+use serde::Serialize;
+use std::collections::BTreeMap;
+use thiserror::Error;
+
+/// Guards the recursive decoder against stack exhaustion on hostile input such as `llllll...`.
+const MAX_DEPTH: usize = 256;
+
+/// A decoded bencode value.
+/// Strings are kept as raw bytes because bencode strings are arbitrary binary data
+/// (e.g. `pieces` or compact `peers`), they are not guaranteed to be valid UTF-8.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// i<integer>e, eg. i52e
+    Int(i64),
+    /// <length>:<contents>, eg. 5:hello
+    Bytes(Vec<u8>),
+    /// l<values>e, eg. l5:helloi52ee
+    List(Vec<Value>),
+    /// d<key><value>...e, eg. d3:foo3:bar5:helloi52ee
+    /// Keys are raw byte strings, the BTreeMap keeps them in the sorted order bencode requires.
+    Dict(BTreeMap<Vec<u8>, Value>),
+}
+
+impl Value {
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        self.as_bytes().and_then(|b| std::str::from_utf8(b).ok())
+    }
+
+    pub fn as_list(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    pub fn as_dict(&self) -> Option<&BTreeMap<Vec<u8>, Value>> {
+        match self {
+            Value::Dict(d) => Some(d),
+            _ => None,
+        }
+    }
+
+    /// Looks up `key` if this value is a dictionary.
+    pub fn get(&self, key: &[u8]) -> Option<&Value> {
+        self.as_dict().and_then(|d| d.get(key))
+    }
+
+    /// Canonical encoding: dictionary keys sorted by raw bytes, integers without leading zeros.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_into(&mut out);
+        out
+    }
+
+    pub fn encode_into(&self, out: &mut Vec<u8>) {
+        match self {
+            Value::Int(n) => {
+                out.push(b'i');
+                out.extend_from_slice(n.to_string().as_bytes());
+                out.push(b'e');
+            }
+            Value::Bytes(b) => encode_bytes(b, out),
+            Value::List(l) => {
+                out.push(b'l');
+                for v in l {
+                    v.encode_into(out);
+                }
+                out.push(b'e');
+            }
+            Value::Dict(d) => {
+                // BTreeMap iterates in key order and cannot hold duplicates
+                out.push(b'd');
+                for (k, v) in d {
+                    encode_bytes(k, out);
+                    v.encode_into(out);
+                }
+                out.push(b'e');
+            }
+        }
+    }
+}
+
+fn encode_bytes(b: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(b.len().to_string().as_bytes());
+    out.push(b':');
+    out.extend_from_slice(b);
+}
+
+impl From<i64> for Value {
+    fn from(n: i64) -> Self {
+        Value::Int(n)
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(b: Vec<u8>) -> Self {
+        Value::Bytes(b)
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(b: &[u8]) -> Self {
+        Value::Bytes(b.to_vec())
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Value::Bytes(s.as_bytes().to_vec())
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(l: Vec<Value>) -> Self {
+        Value::List(l)
+    }
+}
+
+impl From<BTreeMap<Vec<u8>, Value>> for Value {
+    fn from(d: BTreeMap<Vec<u8>, Value>) -> Self {
+        Value::Dict(d)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum EncodeError {
+    #[error("serialize value: {0}")]
+    Serialize(#[from] serde_bencode::Error),
+    #[error("serialized value is not valid bencode: {0}")]
+    Invalid(#[from] DecodeError),
+}
+
+/// Converts any `Serialize` type into a `Value`.
+/// Fails if the type serializes to something bencode cannot represent canonically,
+/// such as two struct fields renamed to the same key.
+pub fn to_value<T: Serialize>(value: &T) -> Result<Value, EncodeError> {
+    let raw = serde_bencode::to_bytes(value)?;
+    Ok(decode(&raw)?)
+}
+
+/// Serializes any `Serialize` type into canonical bencode, see `Value::encode`.
+pub fn to_bytes<T: Serialize>(value: &T) -> Result<Vec<u8>, EncodeError> {
+    Ok(to_value(value)?.encode())
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+#[error("{kind} at byte {offset}")]
+pub struct DecodeError {
+    /// position in the input where decoding failed
+    pub offset: usize,
+    pub kind: DecodeErrorKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum DecodeErrorKind {
+    #[error("unexpected end of input")]
+    UnexpectedEof,
+    #[error("unexpected byte {0:#04x}")]
+    UnexpectedByte(u8),
+    #[error("invalid integer")]
+    InvalidInteger,
+    #[error("invalid string length")]
+    InvalidLength,
+    #[error("dictionary key is not a byte string")]
+    NonStringKey,
+    #[error("duplicate dictionary key")]
+    DuplicateKey,
+    #[error("nesting deeper than {MAX_DEPTH} levels")]
+    TooDeep,
+    #[error("trailing data after value")]
+    TrailingData,
+}
+
+/// Decodes exactly one value, the whole input must be consumed.
+pub fn decode(input: &[u8]) -> Result<Value, DecodeError> {
+    let (value, consumed) = decode_prefix(input)?;
+    if consumed != input.len() {
+        return Err(DecodeError {
+            offset: consumed,
+            kind: DecodeErrorKind::TrailingData,
+        });
+    }
+    Ok(value)
+}
+
+/// Decodes one value from the start of `input` and returns it together with the number of bytes it took up.
+pub fn decode_prefix(input: &[u8]) -> Result<(Value, usize), DecodeError> {
+    let mut decoder = Decoder { input, pos: 0 };
+    let value = decoder.value(0)?;
+    Ok((value, decoder.pos))
+}
+
+/// Finds `key` in the top-level dictionary of `input` and returns the exact bytes of its value.
+/// Useful when the original encoding matters, e.g. the info hash is taken over the `info` dictionary
+/// exactly as it appears in the .torrent file, including keys we don't model.
+pub fn dict_entry<'a>(input: &'a [u8], key: &[u8]) -> Result<Option<&'a [u8]>, DecodeError> {
+    let mut decoder = Decoder { input, pos: 0 };
+    match decoder.peek()? {
+        b'd' => decoder.pos += 1,
+        b => return Err(decoder.error(DecodeErrorKind::UnexpectedByte(b))),
+    }
+    while decoder.peek()? != b'e' {
+        if !decoder.peek()?.is_ascii_digit() {
+            return Err(decoder.error(DecodeErrorKind::NonStringKey));
+        }
+        let k = decoder.bytes()?;
+        let start = decoder.pos;
+        decoder.value(1)?;
+        if k == key {
+            return Ok(Some(&input[start..decoder.pos]));
+        }
+    }
+    Ok(None)
+}
+
+struct Decoder<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn error(&self, kind: DecodeErrorKind) -> DecodeError {
+        DecodeError {
+            offset: self.pos,
+            kind,
+        }
+    }
+
+    fn peek(&self) -> Result<u8, DecodeError> {
+        self.input
+            .get(self.pos)
+            .copied()
+            .ok_or_else(|| self.error(DecodeErrorKind::UnexpectedEof))
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, DecodeError> {
+        if depth > MAX_DEPTH {
+            return Err(self.error(DecodeErrorKind::TooDeep));
+        }
+        match self.peek()? {
+            b'i' => self.int().map(Value::Int),
+            b'0'..=b'9' => self.bytes().map(|b| Value::Bytes(b.to_vec())),
+            b'l' => {
+                self.pos += 1;
+                let mut values = Vec::new();
+                while self.peek()? != b'e' {
+                    values.push(self.value(depth + 1)?);
+                }
+                self.pos += 1;
+                Ok(Value::List(values))
+            }
+            b'd' => {
+                self.pos += 1;
+                let mut dict = BTreeMap::new();
+                while self.peek()? != b'e' {
+                    let key_offset = self.pos;
+                    if !self.peek()?.is_ascii_digit() {
+                        return Err(self.error(DecodeErrorKind::NonStringKey));
+                    }
+                    let key = self.bytes()?.to_vec();
+                    let value = self.value(depth + 1)?;
+                    if dict.insert(key, value).is_some() {
+                        return Err(DecodeError {
+                            offset: key_offset,
+                            kind: DecodeErrorKind::DuplicateKey,
+                        });
+                    }
+                }
+                self.pos += 1;
+                Ok(Value::Dict(dict))
+            }
+            b => Err(self.error(DecodeErrorKind::UnexpectedByte(b))),
+        }
+    }
+
+    /// Reads the digits up to `terminator` and leaves `pos` just past it.
+    fn digits_until(&mut self, terminator: u8) -> Result<&'a str, DecodeError> {
+        let start = self.pos;
+        let len = self.input[start..]
+            .iter()
+            .position(|&b| b == terminator)
+            .ok_or(DecodeError {
+                offset: self.input.len(),
+                kind: DecodeErrorKind::UnexpectedEof,
+            })?;
+        self.pos = start + len + 1;
+        // the callers only accept ascii digits and '-', so anything else fails parsing below
+        std::str::from_utf8(&self.input[start..start + len]).map_err(|_| DecodeError {
+            offset: start,
+            kind: DecodeErrorKind::InvalidInteger,
+        })
+    }
+
+    // decode integers eg. i52e, leading zeros and -0 are not allowed
+    fn int(&mut self) -> Result<i64, DecodeError> {
+        self.pos += 1;
+        let start = self.pos;
+        let digits = self.digits_until(b'e')?;
+        let invalid = DecodeError {
+            offset: start,
+            kind: DecodeErrorKind::InvalidInteger,
+        };
+        let unsigned = digits.strip_prefix('-').unwrap_or(digits);
+        if unsigned.is_empty()
+            || !unsigned.bytes().all(|b| b.is_ascii_digit())
+            || (unsigned.starts_with('0') && digits != "0")
+        {
+            return Err(invalid);
+        }
+        digits.parse::<i64>().map_err(|_| invalid)
+    }
+
+    // decode strings eg. 5:hello
+    fn bytes(&mut self) -> Result<&'a [u8], DecodeError> {
+        let start = self.pos;
+        let digits = self.digits_until(b':')?;
+        let invalid = DecodeError {
+            offset: start,
+            kind: DecodeErrorKind::InvalidLength,
+        };
+        if digits.is_empty()
+            || !digits.bytes().all(|b| b.is_ascii_digit())
+            || (digits.starts_with('0') && digits != "0")
+        {
+            return Err(invalid);
+        }
+        let len = digits.parse::<usize>().map_err(|_| invalid)?;
+        if self.input.len() - self.pos < len {
+            return Err(DecodeError {
+                offset: self.input.len(),
+                kind: DecodeErrorKind::UnexpectedEof,
+            });
+        }
+        let bytes = &self.input[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(input: &[u8]) -> (usize, DecodeErrorKind) {
+        let e = decode(input).unwrap_err();
+        (e.offset, e.kind)
+    }
+
+    #[test]
+    fn decodes_every_type() {
+        let value = decode(b"d4:listli1ei-2ee3:numi52e3:str5:helloe").unwrap();
+        assert_eq!(value.get(b"num").and_then(Value::as_int), Some(52));
+        assert_eq!(value.get(b"str").and_then(Value::as_str), Some("hello"));
+        assert_eq!(value.get(b"list").and_then(Value::as_list), Some(&[Value::Int(1), Value::Int(-2)][..]));
+        assert_eq!(decode(b"i0e").unwrap(), Value::Int(0));
+        assert_eq!(decode(b"0:").unwrap(), Value::Bytes(Vec::new()));
+    }
+
+    #[test]
+    fn rejects_integers_that_are_not_canonical() {
+        assert_eq!(error(b"i03e"), (1, DecodeErrorKind::InvalidInteger));
+        assert_eq!(error(b"i-0e"), (1, DecodeErrorKind::InvalidInteger));
+        assert_eq!(error(b"i-05e"), (1, DecodeErrorKind::InvalidInteger));
+        assert_eq!(error(b"ie"), (1, DecodeErrorKind::InvalidInteger));
+        assert_eq!(error(b"i1.5e"), (1, DecodeErrorKind::InvalidInteger));
+        assert_eq!(error(b"i99999999999999999999e"), (1, DecodeErrorKind::InvalidInteger));
+        assert_eq!(error(b"li1ei12"), (7, DecodeErrorKind::UnexpectedEof));
+    }
+
+    #[test]
+    fn rejects_truncated_strings() {
+        assert_eq!(error(b"5:hel"), (5, DecodeErrorKind::UnexpectedEof));
+        assert_eq!(error(b"l5:hel"), (6, DecodeErrorKind::UnexpectedEof));
+        assert_eq!(error(b"5"), (1, DecodeErrorKind::UnexpectedEof));
+        assert_eq!(error(b"05:hello"), (0, DecodeErrorKind::InvalidLength));
+    }
+
+    #[test]
+    fn rejects_huge_lengths_without_allocating() {
+        assert_eq!(error(b"18446744073709551615:x"), (22, DecodeErrorKind::UnexpectedEof));
+        assert_eq!(error(b"99999999999999999999999:x"), (0, DecodeErrorKind::InvalidLength));
+    }
+
+    #[test]
+    fn rejects_bad_dictionaries() {
+        assert_eq!(error(b"di1ei2ee"), (1, DecodeErrorKind::NonStringKey));
+        assert_eq!(error(b"d1:ai1e1:bi2e1:ai3ee"), (13, DecodeErrorKind::DuplicateKey));
+        assert_eq!(error(b"d1:a"), (4, DecodeErrorKind::UnexpectedEof));
+        assert_eq!(error(b"x"), (0, DecodeErrorKind::UnexpectedByte(b'x')));
+    }
+
+    #[test]
+    fn limits_nesting() {
+        let deep = |depth: usize| [vec![b'l'; depth], vec![b'e'; depth]].concat();
+        assert!(decode(&deep(MAX_DEPTH + 1)).is_ok());
+        assert_eq!(error(&deep(MAX_DEPTH + 2)), (MAX_DEPTH + 1, DecodeErrorKind::TooDeep));
+        // no stack overflow however deep it goes
+        assert_eq!(error(&vec![b'l'; 1_000_000]).1, DecodeErrorKind::TooDeep);
+    }
+
+    #[test]
+    fn rejects_trailing_data() {
+        assert_eq!(error(b"i1ei2e"), (3, DecodeErrorKind::TrailingData));
+        assert_eq!(decode_prefix(b"i1ei2e").unwrap(), (Value::Int(1), 3));
+        assert_eq!(DecodeError { offset: 3, kind: DecodeErrorKind::TrailingData }.to_string(), "trailing data after value at byte 3");
+    }
+
+    #[test]
+    fn keeps_binary_strings_as_they_are() {
+        let value = decode(b"d3:\xff\xfe\x004:\x00\x80\xc3\x28e").unwrap();
+        let bytes = value.get(b"\xff\xfe\x00").and_then(Value::as_bytes).unwrap();
+        assert_eq!(bytes, b"\x00\x80\xc3\x28");
+        assert_eq!(value.get(b"\xff\xfe\x00").and_then(Value::as_str), None);
+    }
+
+    #[test]
+    fn finds_the_raw_bytes_of_a_dictionary_entry() {
+        let input = b"d8:announce3:url4:infod6:lengthi1e7:privatei1eee";
+        assert_eq!(dict_entry(input, b"info").unwrap(), Some(&b"d6:lengthi1e7:privatei1ee"[..]));
+        assert_eq!(dict_entry(input, b"missing").unwrap(), None);
+        assert_eq!(dict_entry(b"li1ee", b"info").unwrap_err().kind, DecodeErrorKind::UnexpectedByte(b'l'));
+    }
+
+    #[test]
+    fn encodes_dictionary_keys_sorted() {
+        let mut dict = BTreeMap::new();
+        for key in ["zebra", "b", "a", "\u{e9}"] {
+            dict.insert(key.as_bytes().to_vec(), Value::from(key));
+        }
+        dict.insert(b"list".to_vec(), Value::from(vec![Value::Int(-3), Value::Int(0)]));
+        assert_eq!(
+            Value::Dict(dict).encode(),
+            "d1:a1:a1:b1:b4:listli-3ei0ee5:zebra5:zebra2:\u{e9}2:\u{e9}e".as_bytes()
+        );
+    }
+
+    #[test]
+    fn serializes_struct_fields_in_key_order() {
+        #[derive(Serialize)]
+        struct Info {
+            name: String,
+            #[serde(rename = "piece length")]
+            plength: u32,
+            length: u64,
+        }
+        let info = Info { name: "file".to_string(), plength: 16384, length: 5 };
+        assert_eq!(to_bytes(&info).unwrap(), b"d6:lengthi5e4:name4:file12:piece lengthi16384ee");
+    }
+
+    #[test]
+    fn refuses_two_fields_with_the_same_key() {
+        #[derive(Serialize)]
+        struct Clash {
+            #[serde(rename = "key")]
+            first: u32,
+            #[serde(rename = "key")]
+            second: u32,
+        }
+        match to_bytes(&Clash { first: 1, second: 2 }) {
+            Err(EncodeError::Invalid(e)) => assert_eq!(e.kind, DecodeErrorKind::DuplicateKey),
+            other => panic!("expected a duplicate key, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn round_trips_through_decode() {
+        for input in [
+            &b"i-42e"[..],
+            b"0:",
+            b"le",
+            b"de",
+            b"d4:infod6:lengthi10e4:name3:\xff\x00\x01e5:peersl6:\x7f\x00\x00\x01\x1a\xe1ee",
+        ] {
+            assert_eq!(decode(input).unwrap().encode(), input);
+        }
+        let value = Value::from(vec![Value::from("a"), Value::Int(i64::MIN), Value::from(&[0xffu8, 0][..])]);
+        assert_eq!(decode(&value.encode()).unwrap(), value);
+    }
+}
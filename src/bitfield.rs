@@ -0,0 +1,104 @@
+/// Which pieces a peer has, kept in the layout of the Bitfield message:
+/// the high bit of the first byte is piece 0, the low bit piece 7, the next byte 8-15, etc.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bitfield(Vec<u8>);
+
+impl Bitfield {
+    /// A bitfield with no pieces set
+    pub fn new(num_pieces: usize) -> Self {
+        Bitfield(vec![0; num_pieces.div_ceil(8)])
+    }
+
+    /// A bitfield with all `num_pieces` set, spare bits at the end stay zero
+    pub fn full(num_pieces: usize) -> Self {
+        let mut bitfield = Bitfield::new(num_pieces);
+        for index in 0..num_pieces as u32 {
+            bitfield.set(index);
+        }
+        bitfield
+    }
+
+    pub fn from_bytes(bytes: Vec<u8>) -> Self {
+        Bitfield(bytes)
+    }
+
+    /// The bitfield of a Bitfield message from a peer, `None` unless it has exactly one bit per piece
+    /// rounded up to whole bytes, with the spare bits at the end cleared (BEP 3)
+    pub fn from_payload(bytes: Vec<u8>, num_pieces: usize) -> Option<Self> {
+        if bytes.len() != num_pieces.div_ceil(8) {
+            return None;
+        }
+        let spare_bits = bytes.len() * 8 - num_pieces;
+        if bytes.last().is_some_and(|&last| last & ((1u16 << spare_bits) - 1) as u8 != 0) {
+            return None;
+        }
+        Some(Bitfield(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn has(&self, index: u32) -> bool {
+        let byte = (index / 8) as usize;
+        let bit = 0x80 >> (index % 8);
+        self.0.get(byte).is_some_and(|b| b & bit != 0)
+    }
+
+    /// Marks `index` as present.
+    /// Returns false and changes nothing if `index` lies beyond the bitfield's bytes, it never grows.
+    /// The spare bits of the last byte can still be set, check indices from peers against the number of pieces first.
+    pub fn set(&mut self, index: u32) -> bool {
+        let Some(byte) = self.0.get_mut((index / 8) as usize) else {
+            return false;
+        };
+        *byte |= 0x80 >> (index % 8);
+        true
+    }
+
+    pub fn clear(&mut self, index: u32) {
+        if let Some(b) = self.0.get_mut((index / 8) as usize) {
+            *b &= !(0x80 >> (index % 8));
+        }
+    }
+
+    /// Indices of all pieces that are set
+    pub fn pieces(&self) -> impl Iterator<Item = u32> + '_ {
+        let end = u32::try_from(self.0.len() * 8).unwrap_or(u32::MAX);
+        (0..end).filter(|&index| self.has(index))
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.iter().map(|b| b.count_ones() as usize).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn set_refuses_indices_beyond_the_end() {
+        let mut bitfield = Bitfield::new(10);
+        assert!(bitfield.set(9));
+        assert!(!bitfield.set(16));
+        assert!(!bitfield.set(u32::MAX));
+        assert_eq!(bitfield.as_bytes().len(), 2);
+    }
+
+    #[test]
+    fn payload_must_fit_the_number_of_pieces() {
+        assert!(Bitfield::from_payload(vec![0xff, 0xc0], 10).is_some());
+        assert!(Bitfield::from_payload(vec![0xff], 8).is_some());
+        assert!(Bitfield::from_payload(vec![], 0).is_some());
+        assert!(Bitfield::from_payload(vec![0xff], 10).is_none(), "too short");
+        assert!(Bitfield::from_payload(vec![0xff, 0xc0, 0], 10).is_none(), "too long");
+        assert!(Bitfield::from_payload(vec![0xff, 0xe0], 10).is_none(), "spare bit set");
+    }
+
+    #[test]
+    fn pieces_lists_the_set_bits() {
+        let bitfield = Bitfield::from_payload(vec![0x81, 0x40], 10).unwrap();
+        assert_eq!(bitfield.pieces().collect::<Vec<_>>(), vec![0, 7, 9]);
+    }
+}
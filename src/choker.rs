@@ -0,0 +1,182 @@
+use rand::seq::SliceRandom;
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// How often the choker reconsiders which peers to unchoke
+pub const CHOKE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The optimistic unchoke moves on every this many rounds, i.e. every 30 seconds
+const OPTIMISTIC_ROUNDS: u32 = 3;
+
+/// Upload slots unless configured otherwise, not counting the optimistic unchoke
+pub const DEFAULT_UPLOAD_SLOTS: usize = 4;
+
+/// What the choker knows about a peer for one round
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerRates {
+    pub addr: SocketAddr,
+    /// whether the peer wants to download from us
+    pub interested: bool,
+    /// bytes the peer sent us since the last round
+    pub downloaded: u64,
+    /// bytes we sent the peer since the last round
+    pub uploaded: u64,
+}
+
+impl PeerRates {
+    pub fn new(addr: SocketAddr) -> Self {
+        PeerRates {
+            addr,
+            interested: false,
+            downloaded: 0,
+            uploaded: 0,
+        }
+    }
+}
+
+/// The peers that may download from us until the next round
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChokeDecision {
+    /// includes the optimistic unchoke
+    pub unchoked: HashSet<SocketAddr>,
+    pub optimistic: Option<SocketAddr>,
+}
+
+/// Tit-for-tat: the interested peers that sent us the most since the last round get the upload slots,
+/// or when seeding the ones that took the most from us.
+/// One more interested peer is unchoked regardless of its rate, so new peers get a chance to show what they give,
+/// it changes every `OPTIMISTIC_ROUNDS` rounds.
+#[derive(Debug, Clone)]
+pub struct Choker {
+    slots: usize,
+    round: u32,
+    optimistic: Option<SocketAddr>,
+}
+
+impl Choker {
+    pub fn new(slots: usize) -> Self {
+        Choker {
+            slots,
+            round: 0,
+            optimistic: None,
+        }
+    }
+
+    /// Number of peers unchoked at a time, the optimistic unchoke included
+    pub fn max_unchoked(&self) -> usize {
+        self.slots + 1
+    }
+
+    /// Decides who is unchoked for the next `CHOKE_INTERVAL`
+    pub fn round(&mut self, peers: &[PeerRates], seeding: bool) -> ChokeDecision {
+        let mut interested: Vec<&PeerRates> = peers.iter().filter(|peer| peer.interested).collect();
+        interested.sort_by_key(|peer| Reverse(if seeding { peer.uploaded } else { peer.downloaded }));
+        let mut unchoked: HashSet<SocketAddr> = interested.iter().take(self.slots).map(|peer| peer.addr).collect();
+
+        let rotate = self.round % OPTIMISTIC_ROUNDS == 0;
+        self.round = self.round.wrapping_add(1);
+        let choked: Vec<SocketAddr> = interested
+            .iter()
+            .map(|peer| peer.addr)
+            .filter(|addr| !unchoked.contains(addr))
+            .collect();
+        let keep = self.optimistic.filter(|addr| !rotate && choked.contains(addr));
+        self.optimistic = keep.or_else(|| {
+            // move on to someone else if there is anyone
+            let others: Vec<SocketAddr> = choked.iter().copied().filter(|&addr| Some(addr) != self.optimistic).collect();
+            let candidates = if others.is_empty() { &choked } else { &others };
+            candidates.choose(&mut rand::thread_rng()).copied()
+        });
+        unchoked.extend(self.optimistic);
+
+        ChokeDecision {
+            unchoked,
+            optimistic: self.optimistic,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peer(port: u16, interested: bool, downloaded: u64, uploaded: u64) -> PeerRates {
+        PeerRates {
+            addr: addr(port),
+            interested,
+            downloaded,
+            uploaded,
+        }
+    }
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn leeching_unchokes_the_peers_that_sent_us_the_most() {
+        // the peers that took the most from us sent the least
+        let peers: Vec<_> = (1..=6).map(|i| peer(i, true, i as u64 * 100, 1000 - i as u64 * 100)).collect();
+        let decision = Choker::new(2).round(&peers, false);
+        assert!(decision.unchoked.contains(&addr(6)) && decision.unchoked.contains(&addr(5)));
+        let optimistic = decision.optimistic.expect("an optimistic unchoke");
+        assert!((1..=4).map(addr).any(|a| a == optimistic));
+        assert_eq!(decision.unchoked.len(), 3);
+    }
+
+    #[test]
+    fn seeding_unchokes_the_peers_that_took_the_most() {
+        let peers: Vec<_> = (1..=6).map(|i| peer(i, true, i as u64 * 100, 1000 - i as u64 * 100)).collect();
+        let decision = Choker::new(2).round(&peers, true);
+        assert!(decision.unchoked.contains(&addr(1)) && decision.unchoked.contains(&addr(2)));
+        assert!((3..=6).map(addr).any(|a| Some(a) == decision.optimistic));
+    }
+
+    #[test]
+    fn uninterested_peers_are_never_unchoked() {
+        let mut peers: Vec<_> = (1..=3).map(|i| peer(i, false, 1_000_000, 1_000_000)).collect();
+        peers.extend((4..=6).map(|i| peer(i, true, 0, 0)));
+        let mut choker = Choker::new(1);
+        for _ in 0..10 {
+            let decision = choker.round(&peers, false);
+            assert!((1..=3).map(addr).all(|a| !decision.unchoked.contains(&a)));
+        }
+        assert_eq!(Choker::new(4).round(&peers[..3], false), ChokeDecision::default());
+    }
+
+    #[test]
+    fn optimistic_unchoke_rotates_every_few_rounds() {
+        // one slot, taken by peer 1, leaves peers 2 to 5 for the optimistic unchoke
+        let peers: Vec<_> = (1..=5).map(|i| peer(i, true, if i == 1 { 100 } else { 0 }, 0)).collect();
+        let mut choker = Choker::new(1);
+        let mut previous = None;
+        for _ in 0..4 {
+            let mut optimistic = Vec::new();
+            for _ in 0..OPTIMISTIC_ROUNDS {
+                let decision = choker.round(&peers, false);
+                assert!(decision.unchoked.contains(&addr(1)));
+                optimistic.push(decision.optimistic.expect("an optimistic unchoke"));
+            }
+            assert!(optimistic.iter().all(|&a| a == optimistic[0]), "kept for {OPTIMISTIC_ROUNDS} rounds");
+            assert_ne!(optimistic[0], addr(1));
+            assert_ne!(Some(optimistic[0]), previous, "moved on to another choked peer");
+            previous = Some(optimistic[0]);
+        }
+    }
+
+    #[test]
+    fn respects_the_number_of_slots() {
+        let peers: Vec<_> = (1..=10).map(|i| peer(i, true, i as u64, 0)).collect();
+        for slots in [0, 1, 3, 9] {
+            let mut choker = Choker::new(slots);
+            let decision = choker.round(&peers, false);
+            assert_eq!(decision.unchoked.len(), choker.max_unchoked());
+        }
+        // fewer interested peers than slots: everyone gets one and nobody is left for the optimistic unchoke
+        let decision = Choker::new(4).round(&peers[..3], false);
+        assert_eq!(decision.unchoked.len(), 3);
+        assert_eq!(decision.optimistic, None);
+    }
+}
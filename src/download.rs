@@ -1,27 +1,1031 @@
-use hex::ToHex;
 use sha1::{Sha1, Digest};
 use tokio::net::TcpStream;
-use crate::{peer::PeerMessage, torrent::{Info, Keys}};
+use tokio::sync::{mpsc, Notify};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use thiserror::Error;
+use crate::bitfield::Bitfield;
+use crate::choker::{Choker, PeerRates, CHOKE_INTERVAL, DEFAULT_UPLOAD_SLOTS};
+use crate::peer::{self, PeerConnection, PeerMessage};
+use crate::picker::PiecePicker;
+use crate::resume::ResumeFile;
+use crate::storage::Storage;
+use crate::torrent::Info;
+use crate::trust::PeerTrust;
 
-// Many blocks form a piece
-// Many pieces form a whole file
-pub async fn download_whole_file(stream: &mut TcpStream, meta_info: &Info) -> Result<Vec<u8>, std::io::Error> {
-    let mut file_data = Vec::new();
-    let num_pieces = meta_info.pieces.0.len();
-    eprintln!("Downloading file with {} pieces", num_pieces);
-    for i in 0..num_pieces as u32 {
-        eprintln!("Starting download for piece: {}", i);
-        let piece_data = download_piece(stream, i, meta_info).await?;
-        file_data.extend_from_slice(&piece_data);
-        eprintln!("Finished download for piece: {}", i);
+/// How long we wait for a peer to accept the connection and complete the handshake
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const BLOCK_SIZE: u32 = 16 << 10; // 16 KiB
+
+/// Longest block we serve, peers asking for more are disconnected
+const MAX_REQUEST_LENGTH: u32 = 128 << 10; // 128 KiB
+
+/// How many block requests of a peer we queue up before ignoring further ones
+const MAX_QUEUED_UPLOADS: usize = 256;
+
+/// How many block requests we keep outstanding on one peer unless configured otherwise
+pub const DEFAULT_WINDOW: usize = 5;
+
+/// Settings of a download
+#[derive(Debug, Clone)]
+pub struct DownloadConfig {
+    /// how many block requests are kept outstanding on each peer, at least 1
+    pub window: usize,
+    /// keep serving peers once all pieces are downloaded instead of disconnecting
+    pub seed: bool,
+    /// how many peers the choker unchokes for their rates, one more is unchoked optimistically
+    pub upload_slots: usize,
+}
+
+impl Default for DownloadConfig {
+    fn default() -> Self {
+        DownloadConfig {
+            window: DEFAULT_WINDOW,
+            seed: false,
+            upload_slots: DEFAULT_UPLOAD_SLOTS,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum DownloadError {
+    #[error("piece {index} failed the hash check: expected {}, got {}", hex::encode(expected), hex::encode(actual))]
+    PieceHashMismatch {
+        index: u32,
+        expected: [u8; 20],
+        actual: [u8; 20],
+    },
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+}
+
+/// A block request, `length` is BLOCK_SIZE except for the last block of the last piece
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Block {
+    pub piece: u32,
+    pub begin: u32,
+    pub length: u32,
+}
+
+impl Block {
+    fn request(&self) -> PeerMessage {
+        PeerMessage::Request { index: self.piece, begin: self.begin, length: self.length }
+    }
+
+    fn cancel(&self) -> PeerMessage {
+        PeerMessage::Cancel { index: self.piece, begin: self.begin, length: self.length }
+    }
+}
+
+/// Block requests sent on one connection and not answered yet.
+/// New requests are only sent while fewer than `window` are outstanding, so the peer always has
+/// the next blocks queued up, whichever piece they belong to, without us asking for more than we can take.
+struct RequestQueue {
+    window: usize,
+    outstanding: Vec<Block>,
+}
+
+impl RequestQueue {
+    fn new(window: usize) -> Self {
+        RequestQueue {
+            window: window.max(1),
+            outstanding: Vec::new(),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.outstanding.len() >= self.window
+    }
+
+    fn push(&mut self, block: Block) {
+        self.outstanding.push(block);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.outstanding.is_empty()
+    }
+
+    /// Forgets all outstanding requests, e.g. because the peer choked us and won't answer them
+    fn take_all(&mut self) -> Vec<Block> {
+        std::mem::take(&mut self.outstanding)
+    }
+
+    /// Removes an answered or cancelled request, false if it wasn't outstanding
+    fn remove(&mut self, block: Block) -> bool {
+        match self.outstanding.iter().position(|&b| b == block) {
+            Some(pos) => {
+                self.outstanding.swap_remove(pos);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Orders for a peer task from the rest of the swarm
+#[derive(Debug, Clone, Copy)]
+enum PeerCommand {
+    /// another peer delivered the block first
+    Cancel(Block),
+    /// the peer got banned for sending corrupt data
+    Disconnect,
+    /// we downloaded and verified a piece, tell the peer
+    Have(u32),
+    /// the choker took the peer's upload slot
+    Choke,
+    /// the choker gave the peer an upload slot
+    Unchoke,
+}
+
+/// The SHA-1 of every block of a piece copy that failed the hash check, with the peer that sent it
+type FailedCopy = Vec<(SocketAddr, [u8; 20])>;
+
+/// A piece that is being downloaded, block by block and possibly from several peers
+struct PartialPiece {
+    block_sizes: Vec<u32>,
+    blocks: Vec<Option<Vec<u8>>>,
+    /// peers with an outstanding request for each block, more than one only in endgame
+    requested: Vec<Vec<SocketAddr>>,
+    /// the peer each received block came from
+    from: Vec<Option<SocketAddr>>,
+}
+
+/// Blocks and pieces still to be downloaded
+struct Work {
+    picker: PiecePicker,
+    partial: HashMap<u32, PartialPiece>,
+    /// set when the download can't go on, e.g. the disk is full
+    failure: Option<std::io::Error>,
+    /// reaches the task of each connected peer
+    commands: HashMap<SocketAddr, mpsc::UnboundedSender<PeerCommand>>,
+    /// copies of pieces that failed the hash check, the piece is requested from other peers first.
+    /// Once a good copy arrives the blocks are compared to find out who sent the bad data.
+    failed_copies: HashMap<u32, Vec<FailedCopy>>,
+    trust: PeerTrust,
+    choker: Choker,
+    /// interest and bytes exchanged since the last choker round of each connected peer
+    rates: HashMap<SocketAddr, PeerRates>,
+    /// peers allowed to download from us
+    unchoked: HashSet<SocketAddr>,
+    /// peers we connect to ourselves whose task is still running
+    outbound: HashSet<SocketAddr>,
+}
+
+impl Work {
+    /// Next block to request from `addr`, in order of preference:
+    /// an unrequested block of a piece that is already started, a block of a new piece from the picker,
+    /// and in endgame, when every missing block is requested already, a block another peer is still busy with.
+    fn next_block(&mut self, addr: SocketAddr, info: &Info) -> Option<Block> {
+        if self.trust.is_banned(addr) {
+            return None;
+        }
+        let (index, i) = self
+            .unrequested_block(addr, info)
+            .or_else(|| if self.in_endgame() { self.endgame_block(addr) } else { None })?;
+
+        let partial = self.partial.get_mut(&index).expect("block of a partial piece");
+        partial.requested[i].push(addr);
+        Some(Block {
+            piece: index,
+            begin: i as u32 * BLOCK_SIZE,
+            length: partial.block_sizes[i],
+        })
+    }
+
+    /// Whether `addr` should leave the piece at `index` to other peers:
+    /// it sent data for a copy that failed the hash check and another connected peer, with a clean record
+    /// for this piece, has it too
+    fn avoid(&self, addr: SocketAddr, index: u32) -> bool {
+        let Some(copies) = self.failed_copies.get(&index) else {
+            return false;
+        };
+        let failed = |peer: SocketAddr| copies.iter().flatten().any(|&(sender, _)| sender == peer);
+        failed(addr) && self.picker.peers_with(index).any(|peer| !failed(peer))
+    }
+
+    fn unrequested_block(&mut self, addr: SocketAddr, info: &Info) -> Option<(u32, usize)> {
+        let wanted = |index: u32| self.picker.peer_has_piece(addr, index) && !self.avoid(addr, index);
+        let started = self
+            .partial
+            .iter()
+            .filter(|(&index, _)| wanted(index))
+            .find_map(|(&index, partial)| {
+                (0..partial.blocks.len())
+                    .find(|&i| partial.blocks[i].is_none() && partial.requested[i].is_empty())
+                    .map(|i| (index, i))
+            });
+        if started.is_some() {
+            return started;
+        }
+
+        let index = self.picker.choose(addr, |index| !self.avoid(addr, index))?;
+        self.picker.start(index);
+        let block_sizes = get_block_sizes(info.piece_size(index), BLOCK_SIZE);
+        let num_blocks = block_sizes.len();
+        self.partial.insert(index, PartialPiece {
+            block_sizes,
+            blocks: vec![None; num_blocks],
+            requested: vec![Vec::new(); num_blocks],
+            from: vec![None; num_blocks],
+        });
+        Some((index, 0))
+    }
+
+    /// Whether every missing block of the whole download is requested from some peer already,
+    /// no matter which peers have the blocks
+    fn in_endgame(&self) -> bool {
+        self.picker.missing() == 0
+            && self.partial.values().all(|partial| {
+                (0..partial.blocks.len()).all(|i| partial.blocks[i].is_some() || !partial.requested[i].is_empty())
+            })
+    }
+
+    fn endgame_block(&self, addr: SocketAddr) -> Option<(u32, usize)> {
+        self.partial
+            .iter()
+            .filter(|(&index, _)| self.picker.peer_has_piece(addr, index) && !self.avoid(addr, index))
+            .flat_map(|(&index, partial)| {
+                (0..partial.blocks.len())
+                    .filter(|&i| partial.blocks[i].is_none() && !partial.requested[i].contains(&addr))
+                    .map(move |i| (partial.requested[i].len(), index, i))
+            })
+            // prefer blocks with the fewest peers on them
+            .min()
+            .map(|(_, index, i)| (index, i))
+    }
+
+    /// Stores a block from `addr` and cancels the same request on other peers.
+    /// Returns the whole piece and the peer that sent each of its blocks once its last block arrived.
+    fn block_received(&mut self, addr: SocketAddr, block: Block, data: Vec<u8>) -> Option<(Vec<u8>, Vec<SocketAddr>)> {
+        if let Some(rates) = self.rates.get_mut(&addr) {
+            rates.downloaded += data.len() as u64;
+        }
+        if self.trust.is_banned(addr) {
+            return None;
+        }
+        let partial = self.partial.get_mut(&block.piece)?;
+        let i = (block.begin / BLOCK_SIZE) as usize;
+        if block.begin % BLOCK_SIZE != 0
+            || i >= partial.blocks.len()
+            || partial.block_sizes[i] != block.length
+            || partial.blocks[i].is_some()
+        {
+            return None;
+        }
+        partial.blocks[i] = Some(data);
+        partial.from[i] = Some(addr);
+        for other in std::mem::take(&mut partial.requested[i]) {
+            if other != addr {
+                if let Some(commands) = self.commands.get(&other) {
+                    let _ = commands.send(PeerCommand::Cancel(block));
+                }
+            }
+        }
+        if partial.blocks.iter().any(Option::is_none) {
+            return None;
+        }
+        let partial = self.partial.remove(&block.piece).expect("partial piece exists");
+        let senders = partial.from.into_iter().flatten().collect();
+        Some((partial.blocks.into_iter().flatten().flatten().collect(), senders))
+    }
+
+    /// Puts a piece that failed the hash check back, remembers the copy and penalizes every peer that sent data for it
+    fn piece_failed(&mut self, piece_index: u32, piece: &[u8], senders: Vec<SocketAddr>) {
+        self.picker.release(piece_index);
+        let contributors: HashSet<SocketAddr> = senders.iter().copied().collect();
+        let newly_banned = self.trust.piece_failed(&contributors);
+        for &addr in &contributors {
+            eprintln!(
+                "Peer {addr} now has {} strike(s) for bad data, trust score {}",
+                self.trust.strikes(addr), self.trust.score(addr)
+            );
+        }
+        for addr in newly_banned {
+            self.ban(addr, "too many pieces failed the hash check");
+        }
+        let copy = senders.into_iter().zip(piece.chunks(BLOCK_SIZE as usize).map(|block| Sha1::digest(block).into())).collect();
+        self.failed_copies.entry(piece_index).or_default().push(copy);
+    }
+
+    /// Credits the peers that sent a piece that passed the hash check.
+    /// Earlier copies of the piece that failed are compared block by block with this one:
+    /// peers that sent a block that differs are banned, the others are forgiven.
+    fn piece_passed(&mut self, piece_index: u32, piece: &[u8], senders: Vec<SocketAddr>) {
+        self.trust.piece_passed(&senders.into_iter().collect());
+        let good: Vec<[u8; 20]> = piece.chunks(BLOCK_SIZE as usize).map(|block| Sha1::digest(block).into()).collect();
+        for copy in self.failed_copies.remove(&piece_index).unwrap_or_default() {
+            let mut guilty: HashSet<SocketAddr> = copy
+                .iter()
+                .zip(&good)
+                .filter(|((_, hash), good)| hash != *good)
+                .map(|(&(addr, _), _)| addr)
+                .collect();
+            let innocent: HashSet<SocketAddr> = copy.iter().map(|&(addr, _)| addr).filter(|addr| !guilty.contains(addr)).collect();
+            for addr in innocent {
+                self.trust.forgive(addr);
+            }
+            guilty.retain(|&addr| !self.trust.is_banned(addr));
+            for addr in guilty {
+                self.ban(addr, "sent a corrupt block");
+            }
+        }
+    }
+
+    /// Bans a peer for the rest of the session and disconnects it
+    fn ban(&mut self, addr: SocketAddr, reason: &str) {
+        self.trust.ban(addr);
+        eprintln!("Banning peer {addr}: {reason}");
+        self.drop_requests(addr);
+        if let Some(commands) = self.commands.get(&addr) {
+            let _ = commands.send(PeerCommand::Disconnect);
+        }
+    }
+
+    /// Tells every connected peer that we have a new piece
+    fn broadcast_have(&self, piece_index: u32) {
+        for commands in self.commands.values() {
+            let _ = commands.send(PeerCommand::Have(piece_index));
+        }
+    }
+
+    /// Records whether a peer wants to download from us.
+    /// Returns true if it should be unchoked right away because an upload slot is free.
+    fn peer_interested(&mut self, addr: SocketAddr, interested: bool) -> bool {
+        if let Some(rates) = self.rates.get_mut(&addr) {
+            rates.interested = interested;
+        }
+        if interested && !self.unchoked.contains(&addr) && self.unchoked.len() < self.choker.max_unchoked() {
+            self.unchoked.insert(addr);
+            return true;
+        }
+        if !interested && self.unchoked.remove(&addr) {
+            self.fill_free_slots();
+        }
+        false
+    }
+
+    /// Unchokes interested peers until the slots are taken, e.g. after a peer went away.
+    /// The choker puts things right on its next round.
+    fn fill_free_slots(&mut self) {
+        let waiting: Vec<SocketAddr> = self
+            .rates
+            .values()
+            .filter(|rates| rates.interested && !self.unchoked.contains(&rates.addr))
+            .map(|rates| rates.addr)
+            .collect();
+        for addr in waiting {
+            if self.unchoked.len() >= self.choker.max_unchoked() {
+                break;
+            }
+            if let Some(commands) = self.commands.get(&addr) {
+                let _ = commands.send(PeerCommand::Unchoke);
+                self.unchoked.insert(addr);
+            }
+        }
+    }
+
+    /// Runs a round of the choker and tells the peers whose slot changed
+    fn choke_round(&mut self) {
+        let peers: Vec<PeerRates> = self.rates.values().copied().collect();
+        let decision = self.choker.round(&peers, self.picker.remaining() == 0);
+        for rates in self.rates.values_mut() {
+            rates.downloaded = 0;
+            rates.uploaded = 0;
+        }
+        if decision.unchoked == self.unchoked {
+            return;
+        }
+        for (addr, commands) in &self.commands {
+            match (self.unchoked.contains(addr), decision.unchoked.contains(addr)) {
+                (true, false) => {
+                    let _ = commands.send(PeerCommand::Choke);
+                }
+                (false, true) => {
+                    let _ = commands.send(PeerCommand::Unchoke);
+                }
+                _ => {}
+            }
+        }
+        eprintln!("Unchoked peers: {:?}, optimistic: {:?}", decision.unchoked, decision.optimistic);
+        self.unchoked = decision.unchoked;
+    }
+
+    /// Forgets all requests of a peer that went away so its blocks can be requested from others
+    fn drop_requests(&mut self, addr: SocketAddr) {
+        for partial in self.partial.values_mut() {
+            for requested in &mut partial.requested {
+                requested.retain(|&other| other != addr);
+            }
+        }
+    }
+}
+
+/// State shared by all peer tasks of one download
+struct Swarm {
+    info: Info,
+    storage: Arc<Storage>,
+    resume: Option<Arc<ResumeFile>>,
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    config: DownloadConfig,
+    work: Mutex<Work>,
+    /// wakes up idle peers when blocks become requestable again, a peer announces new pieces or the download is finished
+    changed: Notify,
+    choker_started: AtomicBool,
+    /// block data we sent and received this session, for the tracker
+    uploaded: AtomicU64,
+    downloaded: AtomicU64,
+    /// number of live `PeerSource`s, while there are any the download waits for new peers instead of giving up
+    peer_sources: AtomicUsize,
+}
+
+impl Swarm {
+    fn work(&self) -> MutexGuard<'_, Work> {
+        self.work.lock().expect("swarm lock poisoned")
+    }
+
+    /// The peer tasks should stop: the download failed, or all pieces are downloaded and we aren't seeding
+    fn is_finished(&self) -> bool {
+        let work = self.work();
+        work.failure.is_some() || (work.picker.remaining() == 0 && !self.config.seed)
+    }
+
+    fn have(&self) -> Bitfield {
+        let work = self.work();
+        let mut have = Bitfield::new(work.picker.num_pieces());
+        for index in (0..work.picker.num_pieces() as u32).filter(|&i| work.picker.is_done(i)) {
+            have.set(index);
+        }
+        have
+    }
+
+    fn uploaded(&self, addr: SocketAddr, bytes: usize) {
+        self.uploaded.fetch_add(bytes as u64, Ordering::Relaxed);
+        if let Some(rates) = self.work().rates.get_mut(&addr) {
+            rates.uploaded += bytes as u64;
+        }
+    }
+
+    /// Whether we have the piece of a requested block and the block lies within it
+    fn can_serve(&self, block: Block) -> bool {
+        let work = self.work();
+        (block.piece as usize) < work.picker.num_pieces()
+            && work.picker.is_done(block.piece)
+            && block.length > 0
+            && block.length <= MAX_REQUEST_LENGTH
+            && block.begin.checked_add(block.length).is_some_and(|end| end <= self.info.piece_size(block.piece))
+    }
+
+    async fn read_block(&self, block: Block) -> Result<Vec<u8>, std::io::Error> {
+        let storage = self.storage.clone();
+        tokio::task::spawn_blocking(move || storage.read_block(block.piece, block.begin, block.length))
+            .await
+            .map_err(std::io::Error::other)?
+    }
+
+    fn next_block(&self, addr: SocketAddr) -> Option<Block> {
+        self.work().next_block(addr, &self.info)
+    }
+
+    fn is_interesting(&self, addr: SocketAddr) -> bool {
+        self.work().picker.is_interesting(addr)
+    }
+
+    /// Makes the blocks requested from a peer that choked us available to the other peers
+    fn choked(&self, addr: SocketAddr) {
+        self.work().drop_requests(addr);
+        self.changed.notify_waiters();
+    }
+
+    fn update_peer(&self, addr: SocketAddr, bitfield: &Bitfield) {
+        if self.work().picker.update_peer(addr, bitfield) {
+            self.changed.notify_waiters();
+        }
+    }
+
+    fn peer_has(&self, addr: SocketAddr, index: u32) {
+        if self.work().picker.peer_has(addr, index) {
+            self.changed.notify_waiters();
+        }
+    }
+
+    async fn block_received(&self, addr: SocketAddr, block: Block, data: Vec<u8>) {
+        self.downloaded.fetch_add(data.len() as u64, Ordering::Relaxed);
+        let Some((piece, senders)) = self.work().block_received(addr, block, data) else {
+            return;
+        };
+
+        // hash and write outside of the lock, the piece stays in flight in the meantime
+        if let Err(e) = verify_piece(&piece, block.piece, &self.info) {
+            eprintln!("{e}, downloading it again");
+            self.work().piece_failed(block.piece, &piece, senders);
+            self.changed.notify_waiters();
+            return;
+        }
+        self.work().piece_passed(block.piece, &piece, senders);
+        let storage = self.storage.clone();
+        let resume = self.resume.clone();
+        let written = tokio::task::spawn_blocking(move || {
+            storage.write_piece(block.piece, &piece)?;
+            match resume {
+                Some(resume) => resume.piece_done(block.piece),
+                None => Ok(()),
+            }
+        })
+        .await
+        .unwrap_or_else(|e| Err(std::io::Error::other(e)));
+
+        let mut work = self.work();
+        match written {
+            Ok(()) => {
+                eprintln!("Downloaded piece {} (last block from {addr})", block.piece);
+                work.picker.complete(block.piece);
+                work.broadcast_have(block.piece);
+                if work.picker.remaining() == 0 {
+                    self.changed.notify_waiters();
+                }
+            }
+            Err(e) => {
+                eprintln!("Writing piece {} failed: {e}", block.piece);
+                work.picker.release(block.piece);
+                work.failure.get_or_insert(e);
+                self.changed.notify_waiters();
+            }
+        }
+    }
+}
+
+/// Keeps a peer known to the swarm for as long as its task runs.
+/// Dropping it releases the peer's outstanding requests, also when the task errors out or panics.
+struct Registration<'a> {
+    swarm: &'a Swarm,
+    addr: SocketAddr,
+}
+
+impl<'a> Registration<'a> {
+    fn new(swarm: &'a Swarm, addr: SocketAddr) -> (Self, mpsc::UnboundedReceiver<PeerCommand>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut work = swarm.work();
+        work.commands.insert(addr, tx);
+        work.rates.insert(addr, PeerRates::new(addr));
+        (Registration { swarm, addr }, rx)
+    }
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        let mut work = self.swarm.work();
+        work.commands.remove(&self.addr);
+        work.rates.remove(&self.addr);
+        if work.unchoked.remove(&self.addr) {
+            work.fill_free_slots();
+        }
+        work.drop_requests(self.addr);
+        work.picker.remove_peer(self.addr);
+        self.swarm.changed.notify_waiters();
+    }
+}
+
+/// A download of the pieces of one torrent.
+/// `run` drives it, the other methods can be used to look at it while it runs or after it finished.
+/// Clones refer to the same download.
+#[derive(Clone)]
+pub struct Download {
+    swarm: Arc<Swarm>,
+}
+
+impl Download {
+    /// Prepares a download to `storage`, skipping the pieces `resume` already has
+    pub fn new(
+        info_hash: [u8; 20],
+        peer_id: [u8; 20],
+        meta_info: &Info,
+        storage: Arc<Storage>,
+        resume: Option<Arc<ResumeFile>>,
+        config: DownloadConfig,
+    ) -> Self {
+        let num_pieces = meta_info.num_pieces();
+        let mut picker = PiecePicker::new(num_pieces);
+        if let Some(resume) = &resume {
+            for index in resume.have().pieces().filter(|&i| (i as usize) < num_pieces) {
+                picker.complete(index);
+            }
+        }
+        let swarm = Arc::new(Swarm {
+            info: meta_info.clone(),
+            storage,
+            resume,
+            info_hash,
+            peer_id,
+            work: Mutex::new(Work {
+                picker,
+                partial: HashMap::new(),
+                failure: None,
+                commands: HashMap::new(),
+                failed_copies: HashMap::new(),
+                trust: PeerTrust::new(),
+                choker: Choker::new(config.upload_slots),
+                rates: HashMap::new(),
+                unchoked: HashSet::new(),
+                outbound: HashSet::new(),
+            }),
+            config,
+            changed: Notify::new(),
+            choker_started: AtomicBool::new(false),
+            uploaded: AtomicU64::new(0),
+            downloaded: AtomicU64::new(0),
+            peer_sources: AtomicUsize::new(0),
+        });
+        Download { swarm }
+    }
+
+    /// Marks pieces as already on disk, e.g. after checking the files with `verify`
+    pub fn mark_have(&self, have: &Bitfield) {
+        let mut work = self.swarm.work();
+        let num_pieces = work.picker.num_pieces();
+        for index in have.pieces().filter(|&i| (i as usize) < num_pieces) {
+            work.picker.complete(index);
+        }
+    }
+
+    pub fn info_hash(&self) -> [u8; 20] {
+        self.swarm.info_hash
+    }
+
+    /// Number of pieces not downloaded yet
+    pub fn remaining(&self) -> usize {
+        self.swarm.work().picker.remaining()
+    }
+
+    /// Bytes of the pieces not downloaded yet
+    pub fn left(&self) -> u64 {
+        let work = self.swarm.work();
+        (0..work.picker.num_pieces() as u32)
+            .filter(|&index| !work.picker.is_done(index))
+            .map(|index| self.swarm.info.piece_size(index) as u64)
+            .sum()
+    }
+
+    /// Bytes of block data sent to peers so far
+    pub fn uploaded(&self) -> u64 {
+        self.swarm.uploaded.load(Ordering::Relaxed)
+    }
+
+    /// Bytes of block data received from peers so far, including blocks that turned out to be corrupt or duplicates
+    pub fn downloaded(&self) -> u64 {
+        self.swarm.downloaded.load(Ordering::Relaxed)
+    }
+
+    /// Waits until every piece is downloaded and verified
+    pub async fn completed(&self) {
+        loop {
+            // register interest before looking at the swarm so a wake up in between isn't lost
+            let changed = self.swarm.changed.notified();
+            if self.remaining() == 0 {
+                return;
+            }
+            changed.await;
+        }
+    }
+
+    /// How much each peer that sent data so far is trusted
+    pub fn trust(&self) -> PeerTrust {
+        self.swarm.work().trust.clone()
+    }
+
+    /// Peers that may download from us, as decided by the choker
+    pub fn unchoked_peers(&self) -> Vec<SocketAddr> {
+        let mut unchoked: Vec<_> = self.swarm.work().unchoked.iter().copied().collect();
+        unchoked.sort();
+        unchoked
+    }
+
+    /// Runs the choker every `CHOKE_INTERVAL` for as long as the download exists, once for all peer tasks
+    fn start_choker(&self) {
+        if self.swarm.choker_started.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let swarm = Arc::downgrade(&self.swarm);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CHOKE_INTERVAL);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                let Some(swarm) = swarm.upgrade() else {
+                    return;
+                };
+                swarm.work().choke_round();
+            }
+        });
     }
-    Ok(file_data)
-} 
 
+    /// Peers banned for sending corrupt data, they are not connected to again
+    pub fn banned_peers(&self) -> Vec<SocketAddr> {
+        self.swarm.work().trust.banned()
+    }
 
-pub async fn download_piece(stream: &mut TcpStream, piece_index: u32, meta_info: &Info) -> Result<Vec<u8>, std::io::Error> {
+    /// Whether a peer on the IP address of `addr` was banned
+    pub fn is_banned(&self, addr: SocketAddr) -> bool {
+        self.swarm.work().trust.is_banned(addr)
+    }
+
+    // Many blocks form a piece
+    // Many pieces form a whole file
+    // Every peer gets its own task which keeps a few block requests outstanding,
+    // preferring the rarest pieces that peer has. Blocks of a peer that goes away are requested from others.
+    // Once every missing block has been requested (endgame), idle peers request the remaining blocks as well
+    // and whoever delivers first wins, the other requests are cancelled.
+    // Verified pieces are written straight to the storage, only pieces in progress are kept in memory.
+    // With a resume file, the pieces it has are skipped and every verified piece is recorded in it.
+    // Peers that keep sending data that fails the hash check are banned.
+    // Peers passed to `add_peers` while it runs join in, and as long as a `PeerSource` is around
+    // it waits for them instead of giving up when the peers it has are gone.
+    pub async fn run(&self, peers: &[SocketAddr]) -> Result<(), std::io::Error> {
+        let num_pieces = self.swarm.info.num_pieces();
+        eprintln!(
+            "Downloading {} of {} pieces from {} peers",
+            self.remaining(), num_pieces, peers.len()
+        );
+        if self.remaining() == 0 {
+            return Ok(());
+        }
+        self.add_peers(peers);
 
-    const BLOCK_SIZE: u32 = 16 << 10; // 16 KiB
+        loop {
+            // register interest before looking at the swarm so a wake up in between isn't lost
+            let changed = self.swarm.changed.notified();
+            if self.swarm.is_finished() {
+                break;
+            }
+            {
+                let work = self.swarm.work();
+                if work.outbound.is_empty()
+                    && work.commands.is_empty()
+                    && self.swarm.peer_sources.load(Ordering::Relaxed) == 0
+                {
+                    break;
+                }
+            }
+            changed.await;
+        }
+
+        {
+            let mut work = self.swarm.work();
+            if let Some(e) = work.failure.take() {
+                return Err(e);
+            }
+            if work.picker.remaining() > 0 {
+                return Err(std::io::Error::other(format!(
+                    "{} of {} pieces could not be downloaded, no peers left that have them",
+                    work.picker.remaining(), num_pieces
+                )));
+            }
+        }
+        let storage = self.swarm.storage.clone();
+        tokio::task::spawn_blocking(move || storage.flush())
+            .await
+            .map_err(std::io::Error::other)?
+    }
+
+    /// Connects to the peers we aren't connected to yet, e.g. the ones a tracker returned on a later announce.
+    /// They join the running download, and are connected to once `run` starts if it didn't yet.
+    pub fn add_peers(&self, peers: &[SocketAddr]) {
+        if self.swarm.is_finished() {
+            return;
+        }
+        self.start_choker();
+        for &addr in peers {
+            {
+                let mut work = self.swarm.work();
+                if work.trust.is_banned(addr) || work.commands.contains_key(&addr) || !work.outbound.insert(addr) {
+                    continue;
+                }
+            }
+            let swarm = self.swarm.clone();
+            tokio::spawn(async move {
+                let _outbound = Outbound { swarm: &swarm, addr };
+                if let Err(e) = peer_worker(addr, &swarm).await {
+                    eprintln!("Peer {addr} failed: {e}");
+                }
+            });
+        }
+    }
+
+    /// Tells the download that new peers may still turn up, see `PeerSource`
+    pub fn peer_source(&self) -> PeerSource {
+        self.swarm.peer_sources.fetch_add(1, Ordering::Relaxed);
+        PeerSource { swarm: self.swarm.clone() }
+    }
+
+    /// Takes over a connection a peer opened to us for this torrent, after `peer::read_handshake`,
+    /// and handles it just like the peers `run` connects to
+    pub async fn accept(&self, stream: TcpStream, addr: SocketAddr) -> Result<(), std::io::Error> {
+        self.start_choker();
+        let connection = peer::accept_peer(stream, &self.swarm.info_hash, self.swarm.peer_id, self.swarm.info.num_pieces()).await?;
+        eprintln!("Accepted peer: {addr}");
+        run_peer(connection, addr, &self.swarm).await
+    }
+}
+
+/// Something that can bring new peers to a download, like a tracker session that keeps announcing.
+/// While one is held, `Download::run` waits for peers instead of failing when it runs out of them.
+pub struct PeerSource {
+    swarm: Arc<Swarm>,
+}
+
+impl Drop for PeerSource {
+    fn drop(&mut self) {
+        self.swarm.peer_sources.fetch_sub(1, Ordering::Relaxed);
+        self.swarm.changed.notify_waiters();
+    }
+}
+
+/// Marks an outgoing connection as in progress until its task ends, also when it errors out or panics
+struct Outbound<'a> {
+    swarm: &'a Swarm,
+    addr: SocketAddr,
+}
+
+impl Drop for Outbound<'_> {
+    fn drop(&mut self) {
+        self.swarm.work().outbound.remove(&self.addr);
+        self.swarm.changed.notify_waiters();
+    }
+}
+
+/// Downloads all pieces of a torrent that `resume` doesn't have yet, see `Download::run`
+pub async fn download_whole_file(
+    peers: &[SocketAddr],
+    info_hash: [u8; 20],
+    peer_id: [u8; 20],
+    meta_info: &Info,
+    storage: Arc<Storage>,
+    resume: Option<Arc<ResumeFile>>,
+) -> Result<(), std::io::Error> {
+    Download::new(info_hash, peer_id, meta_info, storage, resume, DownloadConfig::default()).run(peers).await
+}
+
+async fn peer_worker(addr: SocketAddr, swarm: &Swarm) -> Result<(), std::io::Error> {
+    let connection = tokio::time::timeout(
+        CONNECT_TIMEOUT,
+        peer::connect_to_peer(&addr.to_string(), &swarm.info_hash, swarm.peer_id, swarm.info.num_pieces()),
+    )
+    .await
+    .map_err(|_| std::io::Error::new(std::io::ErrorKind::TimedOut, "connect timed out"))??;
+    eprintln!("Connected to peer: {addr}");
+    run_peer(connection, addr, swarm).await
+}
+
+/// The exchange with one peer, whoever opened the connection:
+/// we request the blocks the swarm hands out for it and serve the blocks it requests from the pieces we have.
+async fn run_peer(mut connection: PeerConnection, addr: SocketAddr, swarm: &Swarm) -> Result<(), std::io::Error> {
+    if swarm.work().trust.is_banned(addr) {
+        return Err(std::io::Error::other("banned for sending corrupt data"));
+    }
+    let (_registration, mut commands) = Registration::new(swarm, addr);
+    swarm.update_peer(addr, &connection.bitfield);
+    let have = swarm.have();
+    if have.count() > 0 {
+        connection.send(&PeerMessage::Bitfield(have.as_bytes().to_vec())).await?;
+    }
+    let mut queue = RequestQueue::new(swarm.config.window);
+    // blocks the peer requested from us and we didn't send yet
+    let mut uploads: VecDeque<Block> = VecDeque::new();
+
+    loop {
+        // register interest before looking at the swarm so a wake up in between isn't lost
+        let changed = swarm.changed.notified();
+        if swarm.is_finished() {
+            return Ok(());
+        }
+        if !connection.state.am_interested || queue.is_empty() {
+            connection.set_interested(swarm.is_interesting(addr)).await?;
+        }
+        request_blocks(&mut connection, swarm, addr, &mut queue).await?;
+
+        tokio::select! {
+            // messages go first so a Cancel reaches the queued uploads before they are sent
+            biased;
+            _ = changed => {}
+            Some(command) = commands.recv() => match command {
+                PeerCommand::Cancel(block) => {
+                    if queue.remove(block) {
+                        connection.send(&block.cancel()).await?;
+                    }
+                }
+                PeerCommand::Disconnect => {
+                    return Err(std::io::Error::other("banned for sending corrupt data"));
+                }
+                PeerCommand::Have(index) => {
+                    connection.send(&PeerMessage::Have(index)).await?;
+                }
+                // a choke drops the peer's requests, it asks again once unchoked
+                PeerCommand::Choke => {
+                    uploads.clear();
+                    if !connection.state.am_choking {
+                        connection.send(&PeerMessage::Choke).await?;
+                    }
+                }
+                PeerCommand::Unchoke => {
+                    if connection.state.am_choking {
+                        connection.send(&PeerMessage::Unchoke).await?;
+                    }
+                }
+            },
+            message = connection.recv() => match message? {
+                PeerMessage::Piece { index, begin, block: data } => {
+                    let block = Block { piece: index, begin, length: data.len() as u32 };
+                    queue.remove(block);
+                    // keep the pipe full while the piece this block completes is hashed and written
+                    request_blocks(&mut connection, swarm, addr, &mut queue).await?;
+                    // blocks we cancelled may still arrive, they are kept if nobody else delivered them yet
+                    swarm.block_received(addr, block, data).await;
+                }
+                PeerMessage::Bitfield(_) => {
+                    swarm.update_peer(addr, &connection.bitfield);
+                }
+                PeerMessage::Have(index) => {
+                    swarm.peer_has(addr, index);
+                }
+                // a choke drops our requests, they go back to the swarm until the peer unchokes us again
+                PeerMessage::Choke => {
+                    queue.take_all();
+                    swarm.choked(addr);
+                }
+                PeerMessage::Interested => {
+                    let free_slot = swarm.work().peer_interested(addr, true);
+                    if free_slot {
+                        connection.send(&PeerMessage::Unchoke).await?;
+                    }
+                }
+                PeerMessage::NotInterested => {
+                    swarm.work().peer_interested(addr, false);
+                }
+                PeerMessage::Request { index, begin, length } => {
+                    let block = Block { piece: index, begin, length };
+                    if !swarm.can_serve(block) {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!("peer requested {length} bytes at {begin} of piece {index}, which we can't serve"),
+                        ));
+                    }
+                    // requests while choked are dropped, as are duplicates and floods
+                    if !connection.state.am_choking && uploads.len() < MAX_QUEUED_UPLOADS && !uploads.contains(&block) {
+                        uploads.push_back(block);
+                    }
+                }
+                PeerMessage::Cancel { index, begin, length } => {
+                    uploads.retain(|&b| b != Block { piece: index, begin, length });
+                }
+                _ => {}
+            },
+            // one block at a time, checking for new messages in between
+            _ = std::future::ready(()), if !uploads.is_empty() => {
+                let block = uploads.pop_front().expect("an upload is queued");
+                let data = swarm.read_block(block).await?;
+                swarm.uploaded(addr, data.len());
+                connection.send(&PeerMessage::Piece { index: block.piece, begin: block.begin, block: data }).await?;
+            }
+        }
+    }
+}
+
+/// Requests blocks for `addr` from the swarm until its window is full or there is nothing left to request
+async fn request_blocks(
+    connection: &mut PeerConnection,
+    swarm: &Swarm,
+    addr: SocketAddr,
+    queue: &mut RequestQueue,
+) -> Result<(), std::io::Error> {
+    while connection.state.can_request() && !queue.is_full() {
+        let Some(block) = swarm.next_block(addr) else {
+            break;
+        };
+        connection.send(&block.request()).await?;
+        queue.push(block);
+    }
+    Ok(())
+}
+
+/// Downloads one piece from one peer, keeping at most `window` block requests outstanding.
+/// Requests are only sent while the peer isn't choking us, a choke puts the outstanding ones back.
+pub async fn download_piece(
+    connection: &mut PeerConnection,
+    piece_index: u32,
+    meta_info: &Info,
+    window: usize,
+) -> Result<Vec<u8>, DownloadError> {
+    if piece_index as usize >= meta_info.num_pieces() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("piece {piece_index} doesn't exist, the torrent has {} pieces", meta_info.num_pieces()),
+        )
+        .into());
+    }
 
     let piece_size = get_piece_size(piece_index, meta_info);
     let block_sizes = get_block_sizes(piece_size, BLOCK_SIZE);
@@ -32,38 +1036,69 @@ pub async fn download_piece(stream: &mut TcpStream, piece_index: u32, meta_info:
     //    [], <--block_sizes[1] 
     //    [], <--block_sizes[2] 
     // ]
-    let mut piece_data: Vec<Vec<u8>> = block_sizes.iter().map(|&size| Vec::with_capacity(size as usize)).collect();
-    let mut offset = 0;
-    for &block_length in &block_sizes {
-        PeerMessage::Request { 
-            index: piece_index, 
-            begin: offset, 
-            length: block_length
-        }.write( stream).await?;
-        offset += block_length;
-    }
+    let mut piece_data: Vec<Vec<u8>> = vec![Vec::new(); block_sizes.len()];
+    let mut unrequested: VecDeque<Block> = block_sizes
+        .iter()
+        .enumerate()
+        .map(|(i, &length)| Block {
+            piece: piece_index,
+            begin: i as u32 * BLOCK_SIZE,
+            length,
+        })
+        .collect();
+    let mut queue = RequestQueue::new(window);
+    connection.set_interested(true).await?;
 
-    for i in 0..block_sizes.len() {
-        let piece_message = PeerMessage::read(stream).await?;
-        if let PeerMessage::Piece { index:_, begin: _, block } = piece_message {
-            piece_data[i] = block;
+    // blocks are placed by their offset, other messages (have, keep alive...) may arrive in between
+    let mut received = 0;
+    while received < block_sizes.len() {
+        while connection.state.can_request() && !queue.is_full() {
+            let Some(block) = unrequested.pop_front() else {
+                break;
+            };
+            connection.send(&block.request()).await?;
+            queue.push(block);
+        }
+        match connection.recv().await? {
+            PeerMessage::Piece { index, begin, block: data } => {
+                // only blocks we still need, including ones requested before a choke that arrive anyway
+                let block = Block { piece: index, begin, length: data.len() as u32 };
+                if !queue.remove(block) {
+                    let Some(pos) = unrequested.iter().position(|&b| b == block) else {
+                        continue;
+                    };
+                    unrequested.remove(pos);
+                }
+                piece_data[(begin / BLOCK_SIZE) as usize] = data;
+                received += 1;
+            }
+            PeerMessage::Choke => {
+                for block in queue.take_all().into_iter().rev() {
+                    unrequested.push_front(block);
+                }
+            }
+            _ => {}
         }
     }
 
     let piece = piece_data.into_iter().flatten().collect::<Vec<u8>>();
-        
-    // validate hash
-    let mut hasher = Sha1::new();
-    hasher.update(&piece);
-    let piece_hash = hasher.finalize();
-    let expected_piece_hash = &meta_info.pieces.0[piece_index as usize];
-
-    assert_eq!(piece_hash.encode_hex::<String>(), expected_piece_hash.encode_hex::<String>());
+    verify_piece(&piece, piece_index, meta_info)?;
 
     Ok(piece)
 
 }
 
+fn verify_piece(piece: &[u8], piece_index: u32, meta_info: &Info) -> Result<(), DownloadError> {
+    let expected = *meta_info.pieces.0.get(piece_index as usize).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("piece {piece_index} doesn't exist"))
+    })?;
+    let actual: [u8; 20] = Sha1::digest(piece).into();
+    if actual != expected {
+        return Err(DownloadError::PieceHashMismatch { index: piece_index, expected, actual });
+    }
+    Ok(())
+}
+
 pub fn get_block_sizes(piece_length: u32, block_size: u32) -> Vec<u32> {
     let mut block_sizes = Vec::new();
     let mut remaining_length = piece_length;
@@ -78,20 +1113,277 @@ pub fn get_block_sizes(piece_length: u32, block_size: u32) -> Vec<u32> {
 }
 
 pub fn get_piece_size(piece_index: u32, meta_info: &Info) -> u32 {
+    meta_info.piece_size(piece_index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{Hashes, Keys};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    const PIECE: u32 = 2 * BLOCK_SIZE;
+
+    /// A single file torrent of `len` bytes and its data
+    fn make_torrent(len: usize) -> (Info, Vec<u8>) {
+        make_torrent_with_pieces(len, PIECE)
+    }
 
-    let length = if let Keys::SingleFile { length } = &meta_info.keys {
-        length
-    } else {
-        todo!()
-    };
+    fn make_torrent_with_pieces(len: usize, plength: u32) -> (Info, Vec<u8>) {
+        let data: Vec<u8> = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+        let pieces = data.chunks(plength as usize).map(|piece| Sha1::digest(piece).into()).collect();
+        let info = Info {
+            name: "fake".to_string(),
+            plength,
+            pieces: Hashes(pieces),
+            keys: Keys::SingleFile { length: len as u64 },
+        };
+        (info, data)
+    }
+
+    #[derive(Clone, Copy)]
+    // the misbehaving peers are only used with several fake peers, see `fake_peer`
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    enum Behaviour {
+        /// serves every block after waiting this long
+        Good(Duration),
+        /// closes the connection instead of serving the block after this many
+        DropAfter(usize),
+        /// flips a bit in every block
+        Corrupt,
+    }
+
+    /// A peer on its own loopback address that has every piece and unchokes whoever is interested.
+    /// Peers are told apart by IP, so the fake peers of one download need different `host`s.
+    /// Only Linux routes all of 127.0.0.0/8 to loopback, elsewhere just host 1 works.
+    async fn fake_peer(host: u8, data: Vec<u8>, behaviour: Behaviour) -> SocketAddr {
+        let listener = TcpListener::bind((std::net::Ipv4Addr::new(127, 0, 0, host), 0)).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(serve(stream, data.clone(), behaviour));
+            }
+        });
+        addr
+    }
 
-    let piece_length = meta_info.plength;
-    let last_piece = length / piece_length; // rounded down even if its 1.9
+    async fn serve(mut stream: TcpStream, data: Vec<u8>, behaviour: Behaviour) -> std::io::Result<()> {
+        // answer with the same handshake, it names the same torrent
+        let mut handshake = [0; 68];
+        stream.read_exact(&mut handshake).await?;
+        stream.write_all(&handshake).await?;
+        let have = Bitfield::full(data.len().div_ceil(PIECE as usize));
+        PeerMessage::Bitfield(have.as_bytes().to_vec()).write(&mut stream).await?;
+        let mut served = 0;
+        loop {
+            match PeerMessage::read(&mut stream).await? {
+                PeerMessage::Interested => PeerMessage::Unchoke.write(&mut stream).await?,
+                PeerMessage::Request { index, begin, length } => {
+                    match behaviour {
+                        Behaviour::Good(delay) => tokio::time::sleep(delay).await,
+                        Behaviour::DropAfter(blocks) if served == blocks => return Ok(()),
+                        _ => {}
+                    }
+                    let offset = (index * PIECE + begin) as usize;
+                    let mut block = data[offset..offset + length as usize].to_vec();
+                    if let Behaviour::Corrupt = behaviour {
+                        block[0] ^= 1;
+                    }
+                    served += 1;
+                    PeerMessage::Piece { index, begin, block }.write(&mut stream).await?;
+                }
+                _ => {}
+            }
+        }
+    }
 
-    if piece_index == last_piece {
-        length % piece_length
-    } else {
-        piece_length
+    /// Downloads from `peers` into a temporary file and returns the download and what ended up on disk
+    #[cfg(target_os = "linux")]
+    async fn download(info: &Info, peers: &[SocketAddr]) -> (Download, Vec<u8>) {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("fake");
+        let storage = Storage::create(&output, info).unwrap();
+        let download = Download::new([1; 20], [2; 20], info, Arc::new(storage), None, DownloadConfig::default());
+        tokio::time::timeout(Duration::from_secs(30), download.run(peers))
+            .await
+            .expect("download timed out")
+            .expect("download failed");
+        (download, std::fs::read(&output).unwrap())
     }
 
-}
\ No newline at end of file
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn finishes_when_a_peer_drops_mid_piece() {
+        let (info, data) = make_torrent(8 * PIECE as usize + 1000);
+        let peers = [
+            fake_peer(2, data.clone(), Behaviour::Good(Duration::from_millis(5))).await,
+            // serves the first block of a piece and goes away before the second
+            fake_peer(3, data.clone(), Behaviour::DropAfter(1)).await,
+            fake_peer(4, data.clone(), Behaviour::Good(Duration::from_millis(5))).await,
+        ];
+        let (download, written) = download(&info, &peers).await;
+        assert_eq!(written, data);
+        assert_eq!(download.remaining(), 0);
+        assert!(download.banned_peers().is_empty());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn bans_a_corrupt_peer_and_completes_the_piece_elsewhere() {
+        let (info, data) = make_torrent(4 * PIECE as usize);
+        let corrupt = fake_peer(5, data.clone(), Behaviour::Corrupt).await;
+        let good = fake_peer(6, data.clone(), Behaviour::Good(Duration::from_millis(20))).await;
+        let (download, written) = download(&info, &[corrupt, good]).await;
+        assert_eq!(written, data);
+        assert_eq!(download.banned_peers(), vec![corrupt]);
+        assert!(download.trust().strikes(corrupt) > 0);
+        assert!(download.trust().score(good) > 0);
+    }
+
+    #[tokio::test]
+    async fn download_piece_refuses_pieces_beyond_the_end() {
+        let (info, data) = make_torrent(3 * PIECE as usize + 1000);
+        let addr = fake_peer(1, data.clone(), Behaviour::Good(Duration::ZERO)).await;
+        let mut connection = peer::connect_to_peer(&addr.to_string(), &[1; 20], [2; 20], info.num_pieces()).await.unwrap();
+        match download_piece(&mut connection, 4, &info, DEFAULT_WINDOW).await {
+            Err(DownloadError::Io(e)) => assert_eq!(e.kind(), std::io::ErrorKind::InvalidInput),
+            other => panic!("expected an invalid input error, got {other:?}"),
+        }
+        let piece = download_piece(&mut connection, 3, &info, DEFAULT_WINDOW).await.unwrap();
+        assert_eq!(piece, data[3 * PIECE as usize..]);
+    }
+
+    #[tokio::test]
+    async fn waits_for_peers_added_while_running() {
+        let (info, data) = make_torrent(3 * PIECE as usize);
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("fake");
+        let storage = Storage::create(&output, &info).unwrap();
+        let download = Download::new([1; 20], [2; 20], &info, Arc::new(storage), None, DownloadConfig::default());
+
+        // nobody to download from yet, but a tracker session may still find someone
+        let source = download.peer_source();
+        let later = download.clone();
+        let peer = fake_peer(1, data.clone(), Behaviour::Good(Duration::ZERO)).await;
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            later.add_peers(&[peer, peer]);
+            drop(source);
+        });
+        tokio::time::timeout(Duration::from_secs(30), download.run(&[]))
+            .await
+            .expect("download timed out")
+            .expect("download failed");
+        assert_eq!(std::fs::read(&output).unwrap(), data);
+    }
+
+    #[tokio::test]
+    async fn gives_up_without_peers_or_a_way_to_find_them() {
+        let (info, _) = make_torrent(PIECE as usize);
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::create(&dir.path().join("fake"), &info).unwrap();
+        let download = Download::new([1; 20], [2; 20], &info, Arc::new(storage), None, DownloadConfig::default());
+        let result = tokio::time::timeout(Duration::from_secs(5), download.run(&[])).await.expect("run kept waiting");
+        assert!(result.unwrap_err().to_string().contains("no peers left"));
+    }
+
+    /// A download seeding `data` and a fake leecher connected to it, past the handshake and our bitfield.
+    /// Also returns the task running our side of the connection, it ends when we hang up on the leecher.
+    async fn leech(
+        info: &Info,
+        data: &[u8],
+    ) -> (Download, TcpStream, tokio::task::JoinHandle<std::io::Result<()>>, tempfile::TempDir) {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::create(&dir.path().join("fake"), info).unwrap();
+        for (index, piece) in data.chunks(info.plength as usize).enumerate() {
+            storage.write_piece(index as u32, piece).unwrap();
+        }
+        let config = DownloadConfig { seed: true, ..DownloadConfig::default() };
+        let download = Download::new([1; 20], [2; 20], info, Arc::new(storage), None, config);
+        download.mark_have(&Bitfield::full(info.num_pieces()));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut leecher = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (mut stream, addr) = listener.accept().await.unwrap();
+        leecher.write_all(&peer::Handshake::new([1; 20], [3; 20]).to_bytes_message()).await.unwrap();
+        peer::read_handshake(&mut stream).await.unwrap();
+        let seeder = download.clone();
+        let task = tokio::spawn(async move { seeder.accept(stream, addr).await });
+
+        let mut handshake = [0; 68];
+        leecher.read_exact(&mut handshake).await.unwrap();
+        assert!(matches!(PeerMessage::read(&mut leecher).await.unwrap(), PeerMessage::Bitfield(_)));
+        PeerMessage::Interested.write(&mut leecher).await.unwrap();
+        assert_eq!(PeerMessage::read(&mut leecher).await.unwrap(), PeerMessage::Unchoke);
+        (download, leecher, task, dir)
+    }
+
+    fn block_of(data: &[u8], plength: u32, index: u32, begin: u32, length: u32) -> PeerMessage {
+        let offset = (index * plength + begin) as usize;
+        PeerMessage::Piece { index, begin, block: data[offset..offset + length as usize].to_vec() }
+    }
+
+    /// Sends a request the seeder must refuse and checks it hangs up without sending anything
+    async fn assert_refused(info: &Info, data: &[u8], index: u32, begin: u32, length: u32) {
+        let (download, mut leecher, task, _dir) = leech(info, data).await;
+        PeerMessage::Request { index, begin, length }.write(&mut leecher).await.unwrap();
+        let error = task.await.unwrap().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+        assert!(PeerMessage::read(&mut leecher).await.is_err(), "expected the connection to be closed");
+        assert_eq!(download.uploaded(), 0);
+    }
+
+    #[tokio::test]
+    async fn serves_requested_blocks_and_counts_them() {
+        let (info, data) = make_torrent(3 * PIECE as usize + 1000);
+        let (download, mut leecher, _task, _dir) = leech(&info, &data).await;
+        let requests = [(0, 0, BLOCK_SIZE), (1, 100, 1000), (3, 0, 1000)];
+        for (index, begin, length) in requests {
+            PeerMessage::Request { index, begin, length }.write(&mut leecher).await.unwrap();
+        }
+        for (index, begin, length) in requests {
+            assert_eq!(PeerMessage::read(&mut leecher).await.unwrap(), block_of(&data, PIECE, index, begin, length));
+        }
+        assert_eq!(download.uploaded(), BLOCK_SIZE as u64 + 2000);
+    }
+
+    #[tokio::test]
+    async fn disconnects_peers_requesting_blocks_outside_the_pieces() {
+        let (info, data) = make_torrent(3 * PIECE as usize + 1000);
+        // past the end of a piece, past the short last piece, a piece we don't have and an empty block
+        assert_refused(&info, &data, 0, PIECE - 10, 20).await;
+        assert_refused(&info, &data, 3, 0, 1001).await;
+        assert_refused(&info, &data, 4, 0, 10).await;
+        assert_refused(&info, &data, 0, 0, 0).await;
+    }
+
+    #[tokio::test]
+    async fn disconnects_peers_requesting_over_long_blocks() {
+        let (info, data) = make_torrent_with_pieces(2 * MAX_REQUEST_LENGTH as usize, 2 * MAX_REQUEST_LENGTH);
+        assert_refused(&info, &data, 0, 0, MAX_REQUEST_LENGTH + 1).await;
+
+        // the longest block we serve is fine
+        let (download, mut leecher, _task, _dir) = leech(&info, &data).await;
+        PeerMessage::Request { index: 0, begin: 0, length: MAX_REQUEST_LENGTH }.write(&mut leecher).await.unwrap();
+        let expected = block_of(&data, info.plength, 0, 0, MAX_REQUEST_LENGTH);
+        assert_eq!(PeerMessage::read(&mut leecher).await.unwrap(), expected);
+        assert_eq!(download.uploaded(), MAX_REQUEST_LENGTH as u64);
+    }
+
+    #[tokio::test]
+    async fn does_not_send_cancelled_blocks() {
+        let (info, data) = make_torrent(2 * PIECE as usize);
+        let (download, mut leecher, _task, _dir) = leech(&info, &data).await;
+        // the cancel arrives together with the request, before the block is read from disk
+        let mut frames = Vec::new();
+        PeerMessage::Request { index: 0, begin: 0, length: BLOCK_SIZE }.write(&mut frames).await.unwrap();
+        PeerMessage::Cancel { index: 0, begin: 0, length: BLOCK_SIZE }.write(&mut frames).await.unwrap();
+        PeerMessage::Request { index: 1, begin: 0, length: 100 }.write(&mut frames).await.unwrap();
+        leecher.write_all(&frames).await.unwrap();
+
+        assert_eq!(PeerMessage::read(&mut leecher).await.unwrap(), block_of(&data, PIECE, 1, 0, 100));
+        assert_eq!(download.uploaded(), 100);
+    }
+}
@@ -0,0 +1,396 @@
+use std::io;
+
+// Decompression of gzip (RFC 1952) and the DEFLATE data inside it (RFC 1951),
+// for trackers that compress their responses. Only decoding is needed, and only for small inputs,
+// so this favours being short over being fast.
+
+const FTEXT_MASK: u8 = 0xe0; // reserved flag bits, must be zero
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31, 35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193, 257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097,
+    6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6, 7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13];
+/// The order in which the code lengths of the code length alphabet are stored in a dynamic block
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("gzip: {msg}"))
+}
+
+/// Decompresses the first member of a gzip file, failing if it would grow beyond `limit` bytes
+pub fn gunzip(data: &[u8], limit: usize) -> io::Result<Vec<u8>> {
+    if data.len() < 18 || data[0] != 0x1f || data[1] != 0x8b {
+        return Err(invalid("not gzip data"));
+    }
+    if data[2] != 8 {
+        return Err(invalid("unknown compression method"));
+    }
+    let flags = data[3];
+    if flags & FTEXT_MASK != 0 {
+        return Err(invalid("reserved flags are set"));
+    }
+    // skip modification time, extra flags and operating system
+    let mut pos = 10;
+    let skip_to = |pos: usize| if pos <= data.len() { Ok(pos) } else { Err(invalid("truncated header")) };
+    if flags & FEXTRA != 0 {
+        let len = data.get(pos..pos + 2).ok_or_else(|| invalid("truncated header"))?;
+        pos = skip_to(pos + 2 + u16::from_le_bytes([len[0], len[1]]) as usize)?;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let end = data[pos..].iter().position(|&b| b == 0).ok_or_else(|| invalid("truncated header"))?;
+            pos += end + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos = skip_to(pos + 2)?;
+    }
+
+    let mut inflater = Inflater {
+        input: BitReader::new(&data[pos..]),
+        output: Vec::new(),
+        limit,
+    };
+    inflater.inflate()?;
+    let trailer = data
+        .get(pos + inflater.input.pos..pos + inflater.input.pos + 8)
+        .ok_or_else(|| invalid("missing trailer"))?;
+    let output = inflater.output;
+    if u32::from_le_bytes(trailer[..4].try_into().expect("4 bytes")) != crc32(&output) {
+        return Err(invalid("checksum mismatch"));
+    }
+    if u32::from_le_bytes(trailer[4..].try_into().expect("4 bytes")) != output.len() as u32 {
+        return Err(invalid("length mismatch"));
+    }
+    Ok(output)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Reads DEFLATE's bit stream, least significant bit first
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// the next byte to load
+    pos: usize,
+    bits: u32,
+    count: u32,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0, bits: 0, count: 0 }
+    }
+
+    fn bits(&mut self, n: u32) -> io::Result<u32> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or_else(|| invalid("unexpected end of data"))?;
+            self.bits |= (byte as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let value = self.bits & ((1 << n) - 1);
+        self.bits >>= n;
+        self.count -= n;
+        Ok(value)
+    }
+
+    /// Drops the rest of the current byte, stored blocks start at a byte boundary
+    fn align(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+    }
+}
+
+/// A canonical Huffman code given by the code length of each symbol
+struct Huffman {
+    /// number of codes of each length
+    counts: [u16; 16],
+    /// symbols ordered by code length, then by value
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        let mut offsets = [0u16; 16];
+        for len in 1..15 {
+            offsets[len + 1] = offsets[len] + counts[len];
+        }
+        let mut symbols = vec![0; lengths.len()];
+        for (symbol, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = symbol as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, input: &mut BitReader) -> io::Result<u16> {
+        // codes of each length are consecutive numbers, starting right after the codes of the previous length
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= input.bits(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(invalid("invalid code"))
+    }
+}
+
+struct Inflater<'a> {
+    input: BitReader<'a>,
+    output: Vec<u8>,
+    limit: usize,
+}
+
+impl Inflater<'_> {
+    fn inflate(&mut self) -> io::Result<()> {
+        loop {
+            let last = self.input.bits(1)? == 1;
+            match self.input.bits(2)? {
+                0 => self.stored()?,
+                1 => {
+                    let (lengths, distances) = fixed_codes();
+                    self.codes(&lengths, &distances)?;
+                }
+                2 => {
+                    let (lengths, distances) = self.dynamic_codes()?;
+                    self.codes(&lengths, &distances)?;
+                }
+                _ => return Err(invalid("invalid block type")),
+            }
+            if last {
+                return Ok(());
+            }
+        }
+    }
+
+    fn push(&mut self, byte: u8) -> io::Result<()> {
+        if self.output.len() >= self.limit {
+            return Err(invalid("decompressed data is too large"));
+        }
+        self.output.push(byte);
+        Ok(())
+    }
+
+    fn stored(&mut self) -> io::Result<()> {
+        self.input.align();
+        let input = &mut self.input;
+        let header = input.data.get(input.pos..input.pos + 4).ok_or_else(|| invalid("unexpected end of data"))?;
+        let len = u16::from_le_bytes([header[0], header[1]]);
+        if len != !u16::from_le_bytes([header[2], header[3]]) {
+            return Err(invalid("corrupt stored block"));
+        }
+        let start = input.pos + 4;
+        let block = input.data.get(start..start + len as usize).ok_or_else(|| invalid("unexpected end of data"))?;
+        input.pos = start + len as usize;
+        if self.output.len() + block.len() > self.limit {
+            return Err(invalid("decompressed data is too large"));
+        }
+        self.output.extend_from_slice(block);
+        Ok(())
+    }
+
+    fn dynamic_codes(&mut self) -> io::Result<(Huffman, Huffman)> {
+        let num_lengths = self.input.bits(5)? as usize + 257;
+        let num_distances = self.input.bits(5)? as usize + 1;
+        let num_code_lengths = self.input.bits(4)? as usize + 4;
+        if num_lengths > 286 || num_distances > 30 {
+            return Err(invalid("too many codes"));
+        }
+        let mut code_lengths = [0u8; 19];
+        for &symbol in &CODE_LENGTH_ORDER[..num_code_lengths] {
+            code_lengths[symbol] = self.input.bits(3)? as u8;
+        }
+        let code_lengths = Huffman::new(&code_lengths);
+
+        let mut lengths = vec![0u8; num_lengths + num_distances];
+        let mut i = 0;
+        while i < lengths.len() {
+            let symbol = code_lengths.decode(&mut self.input)?;
+            let (len, repeat) = match symbol {
+                0..=15 => (symbol as u8, 1),
+                16 => {
+                    let previous = *lengths[..i].last().ok_or_else(|| invalid("repeat without a length"))?;
+                    (previous, 3 + self.input.bits(2)? as usize)
+                }
+                17 => (0, 3 + self.input.bits(3)? as usize),
+                _ => (0, 11 + self.input.bits(7)? as usize),
+            };
+            if i + repeat > lengths.len() {
+                return Err(invalid("too many code lengths"));
+            }
+            lengths[i..i + repeat].fill(len);
+            i += repeat;
+        }
+        if lengths[256] == 0 {
+            return Err(invalid("no end of block code"));
+        }
+        Ok((Huffman::new(&lengths[..num_lengths]), Huffman::new(&lengths[num_lengths..])))
+    }
+
+    /// Decodes literals and back references until the end of the block
+    fn codes(&mut self, lengths: &Huffman, distances: &Huffman) -> io::Result<()> {
+        loop {
+            let symbol = lengths.decode(&mut self.input)? as usize;
+            match symbol {
+                0..=255 => self.push(symbol as u8)?,
+                256 => return Ok(()),
+                _ => {
+                    let index = symbol - 257;
+                    if index >= LENGTH_BASE.len() {
+                        return Err(invalid("invalid length code"));
+                    }
+                    let len = LENGTH_BASE[index] as usize + self.input.bits(LENGTH_EXTRA[index] as u32)? as usize;
+                    let index = distances.decode(&mut self.input)? as usize;
+                    if index >= DIST_BASE.len() {
+                        return Err(invalid("invalid distance code"));
+                    }
+                    let distance = DIST_BASE[index] as usize + self.input.bits(DIST_EXTRA[index] as u32)? as usize;
+                    if distance > self.output.len() {
+                        return Err(invalid("distance reaches before the start"));
+                    }
+                    // the copy may overlap the bytes it produces
+                    for _ in 0..len {
+                        self.push(self.output[self.output.len() - distance])?;
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn fixed_codes() -> (Huffman, Huffman) {
+    let mut lengths = [0u8; 288];
+    lengths[..144].fill(8);
+    lengths[144..256].fill(9);
+    lengths[256..280].fill(7);
+    lengths[280..].fill(8);
+    (Huffman::new(&lengths), Huffman::new(&[5; 30]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `gzip -c small.txt` of "hello hello hello hello\n", a fixed Huffman block after an FNAME header
+    const FIXED: [u8; 39] = [
+        0x1f, 0x8b, 0x08, 0x08, 0xd0, 0xe9, 0xd3, 0x6a, 0x00, 0x03, 0x73, 0x6d, 0x61, 0x6c, 0x6c, 0x2e, 0x74, 0x78,
+        0x74, 0x00, 0xcb, 0x48, 0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0xb9, 0x00, 0x00, 0x88, 0x59, 0x0b, 0x18,
+        0x00, 0x00, 0x00,
+    ];
+    /// `gzip -c -n` of `dynamic_text()`, a dynamic Huffman block
+    const DYNAMIC: [u8; 88] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x03, 0x5d, 0xce, 0x39, 0x0a, 0xc0, 0x30, 0x0c, 0x04,
+        0xc0, 0x2f, 0xf9, 0x90, 0xe4, 0xe3, 0x37, 0x01, 0x2b, 0x60, 0x70, 0xf2, 0xff, 0x32, 0x45, 0x30, 0x78, 0xb7,
+        0x9c, 0x6e, 0x86, 0xf4, 0xf7, 0x7a, 0x5c, 0xfb, 0x3d, 0x97, 0x07, 0x1f, 0x27, 0x23, 0x32, 0x21, 0x33, 0x52,
+        0x90, 0x8a, 0x34, 0x64, 0x41, 0x56, 0x64, 0xdb, 0xb4, 0xbf, 0x11, 0xc8, 0x91, 0x9c, 0xc8, 0x99, 0x2c, 0x64,
+        0x25, 0x1b, 0xb9, 0x90, 0x2b, 0xb9, 0xf9, 0x07, 0x3c, 0xa5, 0x11, 0xbe, 0x36, 0x01, 0x00, 0x00,
+    ];
+    /// "stored as is" in a stored block, as written at compression level 0
+    const STORED: [u8; 35] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x04, 0x03, 0x01, 0x0c, 0x00, 0xf3, 0xff, 0x73, 0x74, 0x6f,
+        0x72, 0x65, 0x64, 0x20, 0x61, 0x73, 0x20, 0x69, 0x73, 0xac, 0x24, 0xbb, 0xe0, 0x0c, 0x00, 0x00, 0x00,
+    ];
+
+    fn dynamic_text() -> Vec<u8> {
+        (0..20).flat_map(|i| format!("d4:name{}:file{i}e", format!("file{i}").len()).into_bytes()).collect()
+    }
+
+    #[test]
+    fn inflates_every_block_type() {
+        assert_eq!(gunzip(&FIXED, 1024).unwrap(), b"hello hello hello hello\n");
+        assert_eq!(gunzip(&DYNAMIC, 1024).unwrap(), dynamic_text());
+        assert_eq!(gunzip(&STORED, 1024).unwrap(), b"stored as is");
+    }
+
+    #[test]
+    fn skips_optional_header_fields() {
+        // the member of FIXED behind FEXTRA, FNAME, FCOMMENT and FHCRC fields
+        let mut data = vec![0x1f, 0x8b, 0x08, FEXTRA | FNAME | FCOMMENT | FHCRC, 0, 0, 0, 0, 0, 3];
+        data.extend_from_slice(&[4, 0, b'A', b'B', 0, 0]);
+        data.extend_from_slice(b"name\0comment\0");
+        data.extend_from_slice(&[0x12, 0x34]);
+        data.extend_from_slice(&FIXED[20..]);
+        assert_eq!(gunzip(&data, 1024).unwrap(), b"hello hello hello hello\n");
+
+        // an extra field running past the end
+        let mut data = vec![0x1f, 0x8b, 0x08, FEXTRA, 0, 0, 0, 0, 0, 3, 0xff, 0xff];
+        data.extend_from_slice(&[0; 10]);
+        assert!(gunzip(&data, 1024).is_err());
+    }
+
+    #[test]
+    fn rejects_truncated_input() {
+        for len in [0, 10, 20, 30, 38] {
+            assert_eq!(gunzip(&FIXED[..len], 1024).unwrap_err().kind(), io::ErrorKind::InvalidData, "{len} bytes");
+        }
+        for len in [20, 60, 87] {
+            assert!(gunzip(&DYNAMIC[..len], 1024).is_err(), "{len} bytes");
+        }
+        assert!(gunzip(&STORED[..25], 1024).is_err());
+    }
+
+    #[test]
+    fn rejects_a_bad_trailer() {
+        let mut data = STORED;
+        data[27] ^= 1;
+        assert!(gunzip(&data, 1024).unwrap_err().to_string().contains("checksum"));
+        let mut data = STORED;
+        data[31] += 1;
+        assert!(gunzip(&data, 1024).unwrap_err().to_string().contains("length"));
+    }
+
+    #[test]
+    fn rejects_other_formats() {
+        assert!(gunzip(b"d8:intervali1800e5:peers0:e", 1024).is_err());
+        let mut data = STORED;
+        data[2] = 7;
+        assert!(gunzip(&data, 1024).is_err());
+        let mut data = STORED;
+        data[3] = 0x20;
+        assert!(gunzip(&data, 1024).is_err());
+    }
+
+    #[test]
+    fn stops_at_the_limit() {
+        let text = dynamic_text();
+        assert_eq!(gunzip(&DYNAMIC, text.len()).unwrap(), text);
+        assert!(gunzip(&DYNAMIC, text.len() - 1).unwrap_err().to_string().contains("too large"));
+        assert!(gunzip(&STORED, 11).unwrap_err().to_string().contains("too large"));
+        assert!(gunzip(&FIXED, 23).is_err());
+    }
+
+    #[test]
+    fn checksum_matches_the_standard() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+}
@@ -1,5 +1,17 @@
+pub mod bencode;
+pub mod bitfield;
 pub mod torrent;
 pub mod tracker;
 pub mod url_encode;
 pub mod peer;
-pub mod download;
\ No newline at end of file
+pub mod download;
+pub mod picker;
+pub mod storage;
+pub mod resume;
+pub mod verify;
+pub mod trust;
+pub mod listener;
+pub mod choker;
+pub mod udp_tracker;
+pub mod gzip;
+pub mod tracker_session;
\ No newline at end of file
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use crate::download::{Download, PeerSource};
+use crate::peer;
+
+/// The port BitTorrent clients traditionally listen on
+pub const DEFAULT_PORT: u16 = 6881;
+
+/// How long a peer that connected to us has to send its handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long we pause after accepting a connection failed, e.g. because we ran out of file descriptors
+const ACCEPT_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Accepts connections from peers for all torrents we are downloading or seeding.
+/// The handshake of a peer names the torrent it wants, the connection is handed to the download of that torrent
+/// and handled just like the connections the download opens itself.
+/// A download keeps waiting for peers for as long as it is added here.
+pub struct Listener {
+    listener: TcpListener,
+    port: u16,
+    torrents: Arc<Mutex<Torrents>>,
+}
+
+/// The downloads peers can join by info hash, each kept waiting for peers while it is here
+type Torrents = HashMap<[u8; 20], (Download, PeerSource)>;
+
+impl Listener {
+    /// Listens on `port` on all interfaces, 0 picks a free port.
+    /// Takes IPv6 peers too where the system allows IPv4 and IPv6 on one socket.
+    pub async fn bind(port: u16) -> io::Result<Self> {
+        let listener = match TcpListener::bind(("::", port)).await {
+            Ok(listener) => listener,
+            Err(_) => TcpListener::bind(("0.0.0.0", port)).await?,
+        };
+        let port = listener.local_addr()?.port();
+        Ok(Listener {
+            listener,
+            port,
+            torrents: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// The port we listen on, to announce to trackers
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Lets peers that connect to us join `download`
+    pub fn add(&self, download: &Download) {
+        self.torrents
+            .lock()
+            .expect("listener lock poisoned")
+            .insert(download.info_hash(), (download.clone(), download.peer_source()));
+    }
+
+    /// Turns away peers for the torrent with `info_hash` from now on
+    pub fn remove(&self, info_hash: &[u8; 20]) {
+        self.torrents.lock().expect("listener lock poisoned").remove(info_hash);
+    }
+
+    /// Accepts connections for as long as it runs, failures to accept one are logged and tried again after a pause
+    pub async fn run(&self) {
+        loop {
+            let (mut stream, addr) = match self.listener.accept().await {
+                Ok(connection) => connection,
+                Err(e) => {
+                    eprintln!("Accepting a peer failed: {e}");
+                    tokio::time::sleep(ACCEPT_BACKOFF).await;
+                    continue;
+                }
+            };
+            // an IPv4 peer reaching a dual-stack socket shows up with an IPv4-mapped IPv6 address
+            let addr = SocketAddr::new(addr.ip().to_canonical(), addr.port());
+            let torrents = self.torrents.clone();
+            tokio::spawn(async move {
+                let result = async {
+                    let handshake = tokio::time::timeout(HANDSHAKE_TIMEOUT, peer::read_handshake(&mut stream))
+                        .await
+                        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))??;
+                    let download = torrents
+                        .lock()
+                        .expect("listener lock poisoned")
+                        .get(&handshake.info_hash)
+                        .map(|(download, _)| download.clone())
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "peer wants a torrent we don't serve"))?;
+                    if download.is_banned(addr) {
+                        return Err(io::Error::other("banned for sending corrupt data"));
+                    }
+                    download.accept(stream, addr).await
+                };
+                if let Err(e) = result.await {
+                    eprintln!("Peer {addr} failed: {e}");
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bitfield::Bitfield;
+    use crate::download::DownloadConfig;
+    use crate::peer::{Handshake, PeerMessage};
+    use crate::storage::Storage;
+    use crate::torrent::{Hashes, Info, Keys};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    const NUM_PIECES: usize = 10;
+
+    fn download(dir: &tempfile::TempDir, config: DownloadConfig) -> Download {
+        let info = Info {
+            name: "fake".to_string(),
+            plength: 16 << 10,
+            pieces: Hashes(vec![[0; 20]; NUM_PIECES]),
+            keys: Keys::SingleFile { length: NUM_PIECES as u64 * (16 << 10) },
+        };
+        let storage = Storage::create(&dir.path().join("fake"), &info).unwrap();
+        Download::new([1; 20], [2; 20], &info, Arc::new(storage), None, config)
+    }
+
+    async fn connect(listener: &Listener, info_hash: [u8; 20]) -> TcpStream {
+        let mut stream = TcpStream::connect(("127.0.0.1", listener.port())).await.unwrap();
+        stream.write_all(&Handshake::new(info_hash, [3; 20]).to_bytes_message()).await.unwrap();
+        stream
+    }
+
+    #[tokio::test]
+    async fn answers_the_handshake_of_a_peer_with_our_pieces() {
+        let dir = tempfile::tempdir().unwrap();
+        let download = download(&dir, DownloadConfig { seed: true, ..DownloadConfig::default() });
+        let mut have = Bitfield::new(NUM_PIECES);
+        have.set(0);
+        have.set(9);
+        download.mark_have(&have);
+        let listener = Arc::new(Listener::bind(0).await.unwrap());
+        listener.add(&download);
+        tokio::spawn({
+            let listener = listener.clone();
+            async move { listener.run().await }
+        });
+
+        let mut stream = connect(&listener, [1; 20]).await;
+        let mut reply = [0; 68];
+        stream.read_exact(&mut reply).await.unwrap();
+        let handshake = Handshake::from_bytes(&reply).unwrap();
+        assert_eq!((handshake.info_hash, handshake.peer_id), ([1; 20], [2; 20]));
+        match PeerMessage::read(&mut stream).await.unwrap() {
+            PeerMessage::Bitfield(bitfield) => assert_eq!(bitfield, have.as_bytes()),
+            other => panic!("expected our bitfield, got {other:?}"),
+        }
+
+        // a torrent we don't serve is hung up on without an answer
+        let mut stream = connect(&listener, [9; 20]).await;
+        assert_eq!(stream.read(&mut reply).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn download_waits_for_peers_while_listening() {
+        let dir = tempfile::tempdir().unwrap();
+        let download = download(&dir, DownloadConfig::default());
+        let listener = Listener::bind(0).await.unwrap();
+        listener.add(&download);
+        // no peers to connect to, but some may still connect to us
+        let run = tokio::time::timeout(Duration::from_millis(200), download.run(&[])).await;
+        assert!(run.is_err(), "the download gave up while listening");
+
+        listener.remove(&download.info_hash());
+        assert!(download.run(&[]).await.is_err());
+    }
+}
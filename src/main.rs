@@ -1,15 +1,23 @@
 use anyhow::Context;
-use bittorrent_starter_rust::download::{download_piece, download_whole_file};
+use bittorrent_starter_rust::bencode;
+use bittorrent_starter_rust::download::{download_piece, Download, DownloadConfig, DEFAULT_WINDOW};
+use bittorrent_starter_rust::listener::{Listener, DEFAULT_PORT};
 use bittorrent_starter_rust::peer::{self, send_handshake};
-use bittorrent_starter_rust::torrent::{Keys, Torrent};
+use bittorrent_starter_rust::resume::{ResumeFile, ResumeState};
+use bittorrent_starter_rust::storage::Storage;
+use bittorrent_starter_rust::torrent::{FileMap, Keys, Torrent};
 use bittorrent_starter_rust::tracker::get_peers;
+use bittorrent_starter_rust::tracker_session::TrackerSession;
+use bittorrent_starter_rust::verify::verify;
 use clap::{Parser, Subcommand};
 use tokio::fs::File;
 use tokio::io::AsyncWriteExt;
-use core::panic;
-use serde_bencode;
 use std::path::PathBuf;
-use tokio;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long we wait for the tracker to take note that we stopped before exiting anyway
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
 
 #[derive(Parser, Debug)]
 struct Args {
@@ -37,76 +45,57 @@ enum Command {
         #[clap(short, long)]
         output: PathBuf,
         torrent: PathBuf,
-        piece_index: u32
+        piece_index: u32,
+        /// Number of block requests kept outstanding on the peer
+        #[clap(long, default_value_t = DEFAULT_WINDOW)]
+        window: usize,
     },
     Download {
         #[clap(short, long)]
         output: PathBuf,
         torrent: PathBuf,
+        /// Trust the pieces recorded in the resume file instead of hashing them again
+        #[clap(long)]
+        trust_resume: bool,
+        /// Number of block requests kept outstanding on each peer
+        #[clap(long, default_value_t = DEFAULT_WINDOW)]
+        window: usize,
+        /// Port to accept peers on while downloading
+        #[clap(short, long, default_value_t = DEFAULT_PORT)]
+        port: u16,
+    },
+    Verify {
+        torrent: PathBuf,
+        /// The downloaded file, or for a multi file torrent the directory it was downloaded to
+        path: PathBuf,
+        /// Number of threads hashing pieces, 0 uses one per core
+        #[clap(short, long, default_value_t = 1)]
+        jobs: usize,
+    },
+    Seed {
+        torrent: PathBuf,
+        /// The complete file, or for a multi file torrent the directory it was downloaded to
+        path: PathBuf,
+        /// Port to accept peers on
+        #[clap(short, long, default_value_t = DEFAULT_PORT)]
+        port: u16,
     }
 }
 
 
-#[allow(dead_code)]
-fn decode_bencoded_value(encoded_value: &str) -> (serde_json::Value, &str) {
-
-    match encoded_value.chars().next() {
-        // decode integers eg. i52e
-        Some('i') => {
-            if let Some((n, rest)) = encoded_value.strip_prefix('i').unwrap().split_once('e') {
-                 if let Ok(n) = n.parse::<i64>() {
-                    return (n.into(), rest)
-                 }
-            } 
-        }
-
-        // decode lists eg. l5:helloe = ["hello"], li25el3:fooi-43ee5:helloe = [25,[ "foo", -43], "hello"]
-        Some('l') => {
-            let mut values = Vec::new();
-            let mut rest = encoded_value.split_at(1).1;
-            while !rest.is_empty() && rest.chars().next().unwrap() != 'e' {
-                let (v, remainder) = decode_bencoded_value(rest);
-                eprint!("v: {:?}, remainder: {:?}\n", v, remainder);
-                values.push(v);
-                rest = remainder;
-            }
-            return (values.into(), &rest[1..])
-        }
-
-        // decode dictionaries eg. d3:foo3:bar5:helloi52ee = {"foo": "bar", "hello": 52}
-        Some('d') => {
-            let mut dict = serde_json::Map::new();
-            let mut rest = encoded_value.split_at(1).1;
-            while !rest.is_empty() && rest.chars().next().unwrap() != 'e' {
-                let (k, remainder) = decode_bencoded_value(rest);
-                let k = match k {
-                    serde_json::Value::String(k) => k,
-                    k => {
-                        panic!("dict keys must be strings, not {k:?}")
-                    }
-                };
-                let (v, remainder) = decode_bencoded_value(remainder);
-                eprint!("k: {k}, v {v}\n");
-                dict.insert(k, v);
-                rest = remainder;
-            }
-            return (dict.into(), &rest[1..])
-        }
-
-        // decode strings eg. 5:hello
-        Some('0'..='9') => {
-            if let Some((len, str)) = encoded_value.split_once(':') {
-                if let Ok(len) = len.parse::<usize>() {
-                    return (str[..len].to_string().into(), &str[len..]);
-                }
-            }
-        }
-
-        _ => {}
-        
+/// Converts a bencode value into json for the decode command.
+/// Byte strings are shown as text, replacing invalid UTF-8 sequences.
+fn bencode_to_json(value: &bencode::Value) -> serde_json::Value {
+    match value {
+        bencode::Value::Int(n) => (*n).into(),
+        bencode::Value::Bytes(b) => String::from_utf8_lossy(b).into(),
+        bencode::Value::List(l) => l.iter().map(bencode_to_json).collect::<Vec<_>>().into(),
+        bencode::Value::Dict(d) => d
+            .iter()
+            .map(|(k, v)| (String::from_utf8_lossy(k).into_owned(), bencode_to_json(v)))
+            .collect::<serde_json::Map<_, _>>()
+            .into(),
     }
-    panic!("Unhandled encoded value: {}", encoded_value);
-
 }
 
 // 
@@ -118,18 +107,26 @@ async fn main() -> anyhow::Result<()> {
 
         // Usage: sh ./your_bittorrent.sh decode "<encoded_value>"
         Command::Decode { value } => {
-            let decoded_value = decode_bencoded_value(&value);
-            println!("{}", decoded_value.0.to_string());
+            let decoded_value = bencode::decode(value.as_bytes()).context("decode bencoded value")?;
+            println!("{}", bencode_to_json(&decoded_value));
         }
 
         // Usage: sh ./your_bittorrent.sh info sample.torrent
         Command::Info { torrent } => {
-            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent = serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+            let t = Torrent::read(torrent)?;
             eprintln!("{t:?}");
             println!("Tracker URL: {}", t.announce);
-            if let Keys::SingleFile { length } = t.info.keys {
-                println!("Length: {length}");
+            match &t.info.keys {
+                Keys::SingleFile { length } => {
+                    println!("Length: {length}");
+                }
+                Keys::MultiFile { .. } => {
+                    println!("Length: {}", t.info.length());
+                    println!("Files:");
+                    for file in FileMap::new(&t.info).files {
+                        println!("{} ({} bytes)", file.path.display(), file.length);
+                    }
+                }
             }
             let info_hash = &t.info_hash();
             let hash_hex = hex::encode(info_hash);
@@ -138,28 +135,27 @@ async fn main() -> anyhow::Result<()> {
             println!("Piece Length: {}", t.info.plength);
             println!("Piece Hashes:");
             for hash in t.info.pieces.0 {
-                println!("{}", hex::encode(&hash))
+                println!("{}", hex::encode(hash))
             };
         }
 
         // Usage: sh ./your_bittorrent.sh peers sample.torrent
         Command::Peers { torrent } => {
-            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent = serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+            let t = Torrent::read(torrent)?;
             let peers = get_peers(
                 String::from("00112233445566778899"),
+                DEFAULT_PORT,
                 &t
             ).await?;
             for peer in &peers.0 {
-                println!("{}:{}", peer.ip(), peer.port());
+                println!("{peer}");
             }
         }
 
         // Usage: sh ./your_bittorrent.sh handshake sample.torrent <peer_ip>:<peer_port>
         // E.g. sh ./your_bittorrent.sh handshake sample.torrent 165.232.41.73:51451
         Command::Handshake { torrent, peer_addr } => {
-            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent = serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+            let t = Torrent::read(torrent)?;
             let handshake_response = send_handshake(&peer_addr, &t.info_hash(), *b"00112233445566778899").await?;
 
             eprintln!("{:?}", handshake_response);
@@ -168,27 +164,43 @@ async fn main() -> anyhow::Result<()> {
         }
 
         // Usage: sh ./your_bittorrent.sh download_piece -o /tmp/test-piece-0 sample.torrent 0
-        Command::DownloadPiece { output, torrent, piece_index } => {
-            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent = serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
+        Command::DownloadPiece { output, torrent, piece_index, window } => {
+            let t = Torrent::read(torrent)?;
 
             let peers = get_peers(
                 String::from("00112233445566778899"),
+                DEFAULT_PORT,
                 &t
             ).await?;
 
-            let first_peer = &peers.0[0];
-            let peer_addr = format!("{}:{}", first_peer.ip(), first_peer.port());
-            
-            let mut peer_connection = peer::connect_to_peer(
-                &peer_addr,
-                &t.info_hash(),
-                *b"00112233445566778899"
-            ).await?;
+            // try the peers one after another until one delivers a piece that passes the hash check
+            let mut piece = None;
+            for peer in &peers.0 {
+                let peer_addr = peer.to_string();
+                let mut peer_connection = match peer::connect_to_peer(
+                    &peer_addr,
+                    &t.info_hash(),
+                    *b"00112233445566778899",
+                    t.info.num_pieces()
+                ).await {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        eprintln!("Could not connect to peer {}: {}", peer_addr, e);
+                        continue;
+                    }
+                };
 
-            eprintln!("Connected to peer: {}", peer_addr);
+                eprintln!("Connected to peer: {}", peer_addr);
 
-            let piece = download_piece(&mut peer_connection, piece_index, &t.info).await?;
+                match download_piece(&mut peer_connection, piece_index, &t.info, window).await {
+                    Ok(data) => {
+                        piece = Some(data);
+                        break;
+                    }
+                    Err(e) => eprintln!("Peer {} failed: {}", peer_addr, e),
+                }
+            }
+            let piece = piece.context("no peer delivered the piece")?;
 
             let mut file = File::create(output).await?;
             file.write_all(&piece).await?;
@@ -196,35 +208,149 @@ async fn main() -> anyhow::Result<()> {
         }
 
         // Usage: sh ./your_bittorrent.sh download -o /tmp/test.txt sample.torrent
-        Command::Download { output, torrent} => {
-            let dot_torrent = std::fs::read(torrent).context("read torrent file")?;
-            let t: Torrent = serde_bencode::from_bytes(&dot_torrent).context("parse torrent file")?;
-
-            let peers = get_peers(
-                String::from("00112233445566778899"),
-                &t
-            ).await?;
-
-            let first_peer = &peers.0[0];
-            let peer_addr = format!("{}:{}", first_peer.ip(), first_peer.port());
-            
-            let mut peer_connection = peer::connect_to_peer(
-                &peer_addr,
-                &t.info_hash(),
-                *b"00112233445566778899"
-            ).await?;
+        // An interrupted download picks up where it left off, using <output>.resume
+        Command::Download { output, torrent, trust_resume, window, port } => {
+            let t = Torrent::read(torrent)?;
+            let storage = Arc::new(Storage::create(&output, &t.info).context("create output files")?);
+
+            let resume_path = ResumeState::path_for(&output);
+            let loaded = match ResumeState::load(&resume_path) {
+                Ok(loaded) => loaded,
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                    eprintln!("Ignoring damaged resume file {}: {e}", resume_path.display());
+                    None
+                }
+                Err(e) => return Err(e).context("read resume file"),
+            };
+            let mut state = match loaded {
+                // a resume file of another torrent, or one cut short, starts over
+                Some(state)
+                    if state.info_hash == t.info_hash()
+                        && state.have.as_bytes().len() == t.info.num_pieces().div_ceil(8) => state,
+                _ => ResumeState::new(t.info_hash(), t.info.num_pieces()),
+            };
+            if !trust_resume {
+                let (storage, info) = (storage.clone(), t.info.clone());
+                state = tokio::task::spawn_blocking(move || {
+                    state.recheck(&storage, &info);
+                    state
+                })
+                .await?;
+            }
+            let have = state.have.count();
+            let resume = Arc::new(ResumeFile::new(resume_path, state));
 
-            eprintln!("Connected to peer: {}", peer_addr);
+            if have < t.info.num_pieces() {
+                if have > 0 {
+                    eprintln!("Resuming with {} of {} pieces", have, t.info.num_pieces());
+                }
+                // peers may connect to us as well, but the download goes on without them if the port is taken
+                let listener = match Listener::bind(port).await {
+                    Ok(listener) => Some(Arc::new(listener)),
+                    Err(e) => {
+                        eprintln!("Not accepting peers, listening on port {port} failed: {e}");
+                        None
+                    }
+                };
+                let download = Download::new(
+                    t.info_hash(),
+                    *b"00112233445566778899",
+                    &t.info,
+                    storage,
+                    Some(resume.clone()),
+                    DownloadConfig { window, ..DownloadConfig::default() },
+                );
+                let mut session = TrackerSession::new(
+                    &t,
+                    String::from("00112233445566778899"),
+                    listener.as_ref().map_or(port, |listener| listener.port()),
+                );
+                let peers = session.start(&download).await?;
+                if let Some(listener) = listener {
+                    listener.add(&download);
+                    tokio::spawn(async move { listener.run().await });
+                }
+                let result = tokio::select! {
+                    result = download.run(&peers.0) => result,
+                    _ = session.keep_announcing(&download) => Ok(()),
+                    _ = tokio::signal::ctrl_c() => Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "interrupted")),
+                };
+                stop_announcing(&mut session, &download).await;
+                for peer in download.banned_peers() {
+                    eprintln!("Banned peer {peer} for sending corrupt data");
+                }
+                result?;
+            }
+            resume.remove().context("remove resume file")?;
+            eprintln!("Downloaded file");
+        }
 
-            let file_vec = download_whole_file(&mut peer_connection, &t.info).await?;
+        // Usage: sh ./your_bittorrent.sh verify sample.torrent /tmp/test.txt
+        Command::Verify { torrent, path, jobs } => {
+            let t = Torrent::read(torrent)?;
+            let storage = Storage::open(&path, &t.info).context("open downloaded files")?;
+            let info = t.info.clone();
+            let report = tokio::task::spawn_blocking(move || verify(&storage, &info, jobs)).await?;
+
+            println!("Valid Pieces: {}/{}", report.valid.count(), report.num_pieces);
+            let failed: Vec<String> = report.failed().map(|index| index.to_string()).collect();
+            if !failed.is_empty() {
+                println!("Failed Pieces: {}", failed.join(" "));
+            }
+            println!("Files:");
+            for file in &report.files {
+                println!("{:>7.2}% {}", file.percent(), file.path.display());
+            }
+            anyhow::ensure!(report.is_complete(), "{} pieces failed the hash check", failed.len());
+        }
 
-            let mut file = File::create(output).await?;
-            file.write_all(&file_vec).await?;
-            eprintln!("Downloaded file");
+        // Usage: sh ./your_bittorrent.sh seed sample.torrent /tmp/test.txt
+        Command::Seed { torrent, path, port } => {
+            let t = Torrent::read(torrent)?;
+            let storage = Arc::new(Storage::open(&path, &t.info).context("open downloaded files")?);
+            let (checked, info) = (storage.clone(), t.info.clone());
+            let report = tokio::task::spawn_blocking(move || verify(&checked, &info, 0)).await?;
+            anyhow::ensure!(
+                report.is_complete(),
+                "only {} of {} pieces are complete, download the rest first",
+                report.valid.count(), report.num_pieces
+            );
+
+            let download = Download::new(
+                t.info_hash(),
+                *b"00112233445566778899",
+                &t.info,
+                storage,
+                None,
+                DownloadConfig { seed: true, ..DownloadConfig::default() },
+            );
+            download.mark_have(&report.valid);
+            let listener = Listener::bind(port).await.context("listen for peers")?;
+            listener.add(&download);
+            eprintln!("Seeding {} on port {}", path.display(), listener.port());
+            // peers that find us some other way can still connect if the tracker can't be reached
+            let mut session = TrackerSession::new(&t, String::from("00112233445566778899"), listener.port());
+            if let Err(e) = session.start(&download).await {
+                eprintln!("Announce failed: {e}");
+            }
+            // seeds until interrupted, the listener and the announces never finish on their own
+            tokio::select! {
+                _ = listener.run() => {}
+                _ = session.keep_announcing(&download) => {}
+                _ = tokio::signal::ctrl_c() => {}
+            }
+            stop_announcing(&mut session, &download).await;
         }
     }
 
     Ok(())
 }
 
-
+/// Tells the tracker we stopped, without keeping the user waiting on a tracker that doesn't answer
+async fn stop_announcing(session: &mut TrackerSession, download: &Download) {
+    match tokio::time::timeout(STOP_TIMEOUT, session.stop(download)).await {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => eprintln!("Announcing that we stopped failed: {e}"),
+        Err(_) => eprintln!("Announcing that we stopped timed out"),
+    }
+}
@@ -1,6 +1,17 @@
+use bytes::{Buf, BufMut, BytesMut};
 use serde::Serialize;
 use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use std::time::Duration;
+use crate::bitfield::Bitfield;
+
+/// A keep-alive is sent when we sent nothing else for this long
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(90);
+
+/// Peers that send nothing, not even keep-alives, for this long are disconnected
+const IDLE_TIMEOUT: Duration = Duration::from_secs(180);
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Handshake {
@@ -44,6 +55,12 @@ impl Handshake {
         }
 
         let protocol_len = bytes[0] as usize;
+        if bytes.len() < 49 + protocol_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Invalid handshake length",
+            ));
+        }
         let protocol_str = String::from_utf8(bytes[1..1 + protocol_len].to_vec())
             .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Invalid protocol string"))?;
         let reserved: [u8; 8] = bytes[1 + protocol_len..9 + protocol_len]
@@ -87,8 +104,10 @@ pub async fn send_handshake(
     Ok(response_handshake)
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum PeerMessage {
+    /// a frame without a message ID, keeps an idle connection open
+    KeepAlive,
     Choke,
     Unchoke,
     Interested,
@@ -110,13 +129,177 @@ pub enum PeerMessage {
         begin: u32,
         length: u32,
     },
+    /// a message we don't support, e.g. from a protocol extension
+    Unknown {
+        id: u8,
+        payload: Vec<u8>,
+    },
+}
+
+/// Who is choking and who is interested on a connection.
+/// Both sides start out choking and not interested, requests are only served while the other side isn't choking.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionState {
+    pub am_choking: bool,
+    pub am_interested: bool,
+    pub peer_choking: bool,
+    pub peer_interested: bool,
+}
+
+impl Default for ConnectionState {
+    fn default() -> Self {
+        ConnectionState {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+        }
+    }
+}
+
+impl ConnectionState {
+    /// Whether the peer would answer our requests now
+    pub fn can_request(&self) -> bool {
+        self.am_interested && !self.peer_choking
+    }
+}
+
+/// An established connection to a peer, after the handshake.
+/// Messages are read and written by background tasks so `send` and `recv` can be used inside `tokio::select!`
+/// without losing half read or half written messages.
+/// The writer sends keep-alives while we have nothing else to say, the reader gives up on a peer that stays silent.
+pub struct PeerConnection {
+    outgoing: mpsc::Sender<PeerMessage>,
+    messages: mpsc::Receiver<io::Result<PeerMessage>>,
+    reader: JoinHandle<()>,
+    writer: JoinHandle<()>,
+    /// the pieces the peer announced through Bitfield and Have messages
+    pub bitfield: Bitfield,
+    /// the number of pieces of the torrent, to check the peer's Bitfield and Have messages against
+    num_pieces: usize,
+    /// kept up to date by `send` and `recv`
+    pub state: ConnectionState,
+}
+
+impl PeerConnection {
+    fn new(stream: TcpStream, num_pieces: usize) -> Self {
+        Self::with_timeouts(stream, num_pieces, KEEP_ALIVE_INTERVAL, IDLE_TIMEOUT)
+    }
+
+    /// A connection that sends a keep-alive after `keep_alive_interval` of silence on our side
+    /// and gives up after `idle_timeout` of silence on the peer's
+    fn with_timeouts(stream: TcpStream, num_pieces: usize, keep_alive_interval: Duration, idle_timeout: Duration) -> Self {
+        let (mut read_half, mut write_half) = stream.into_split();
+        let (tx, messages) = mpsc::channel(32);
+        let reader = tokio::spawn(async move {
+            let codec = PeerCodec::default();
+            let mut buf = BytesMut::new();
+            loop {
+                let message = tokio::time::timeout(idle_timeout, codec.read(&mut read_half, &mut buf))
+                    .await
+                    .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::TimedOut, "peer sent nothing for too long")));
+                let failed = message.is_err();
+                if tx.send(message).await.is_err() || failed {
+                    return;
+                }
+            }
+        });
+        let (outgoing, mut rx) = mpsc::channel(32);
+        let writer = tokio::spawn(async move {
+            loop {
+                let message = match tokio::time::timeout(keep_alive_interval, rx.recv()).await {
+                    Ok(Some(message)) => message,
+                    Ok(None) => return,
+                    Err(_) => PeerMessage::KeepAlive,
+                };
+                if message.write(&mut write_half).await.is_err() {
+                    return;
+                }
+            }
+        });
+        PeerConnection {
+            outgoing,
+            messages,
+            reader,
+            writer,
+            bitfield: Bitfield::new(num_pieces),
+            num_pieces,
+            state: ConnectionState::default(),
+        }
+    }
+
+    /// Queues a message for the peer, this is cancel safe.
+    /// Fails once the connection is closed, e.g. because an earlier write failed.
+    pub async fn send(&mut self, message: &PeerMessage) -> io::Result<()> {
+        self.outgoing
+            .send(message.clone())
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "connection to peer closed"))?;
+        match message {
+            PeerMessage::Choke => self.state.am_choking = true,
+            PeerMessage::Unchoke => self.state.am_choking = false,
+            PeerMessage::Interested => self.state.am_interested = true,
+            PeerMessage::NotInterested => self.state.am_interested = false,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Tells the peer whether we want something from it, if that changed
+    pub async fn set_interested(&mut self, interested: bool) -> io::Result<()> {
+        match (interested, self.state.am_interested) {
+            (true, false) => self.send(&PeerMessage::Interested).await,
+            (false, true) => self.send(&PeerMessage::NotInterested).await,
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits for the next message from the peer, this is cancel safe.
+    /// Bitfield and Have messages are applied to `bitfield`, choke and interest messages to `state`,
+    /// before being returned. Fails on a Bitfield or Have message that doesn't fit the number of pieces.
+    pub async fn recv(&mut self) -> io::Result<PeerMessage> {
+        let message = self
+            .messages
+            .recv()
+            .await
+            .unwrap_or_else(|| Err(io::ErrorKind::UnexpectedEof.into()))?;
+        match &message {
+            PeerMessage::Bitfield(bytes) => {
+                self.bitfield = Bitfield::from_payload(bytes.clone(), self.num_pieces).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "bitfield doesn't match the number of pieces")
+                })?;
+            }
+            PeerMessage::Have(index) if *index as usize >= self.num_pieces => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("peer has piece {index}, which doesn't exist")));
+            }
+            PeerMessage::Have(index) => {
+                self.bitfield.set(*index);
+            }
+            PeerMessage::Choke => self.state.peer_choking = true,
+            PeerMessage::Unchoke => self.state.peer_choking = false,
+            PeerMessage::Interested => self.state.peer_interested = true,
+            PeerMessage::NotInterested => self.state.peer_interested = false,
+            _ => {}
+        }
+        Ok(message)
+    }
 }
 
+impl Drop for PeerConnection {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.writer.abort();
+    }
+}
+
+/// Connects and exchanges handshakes with a peer serving the torrent with `info_hash`, which has `num_pieces` pieces.
+/// We start out not interested and choked, see `ConnectionState`.
 pub async fn connect_to_peer(
     addr: &str,
     info_hash: &[u8; 20],
     peer_id: [u8; 20],
-) -> io::Result<TcpStream> {
+    num_pieces: usize,
+) -> io::Result<PeerConnection> {
     let handshake = Handshake::new(*info_hash, peer_id);
     let handshake_bytes_message = handshake.to_bytes_message();
 
@@ -127,24 +310,40 @@ pub async fn connect_to_peer(
     // Read the response from the peer
     let mut response = vec![0; 68]; // Handshake response is 68 bytes
     stream.read_exact(&mut response).await?;
+    if Handshake::from_bytes(&response)?.info_hash != *info_hash {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "peer serves a different torrent"));
+    }
 
-    // expect first message to be a bitfield message
-    assert!(matches!(
-        PeerMessage::read(&mut stream).await?,
-        PeerMessage::Bitfield(_)
-    ));
+    // the peer's bitfield, unchoke etc. are picked up by whoever reads from the connection
+    Ok(PeerConnection::new(stream, num_pieces))
+}
+
+/// Largest frame a peer may send us, leaves room for blocks far bigger than the usual 16 KiB
+/// and for the bitfields of torrents with millions of pieces
+pub const MAX_FRAME_SIZE: usize = 2 << 20;
 
-    // send an interested message
-    PeerMessage::Interested.write(&mut stream).await?;
+/// Reads the handshake of a peer that connected to us, it names the torrent the peer wants
+pub async fn read_handshake(stream: &mut TcpStream) -> io::Result<Handshake> {
+    let mut request = vec![0; 68];
+    stream.read_exact(&mut request).await?;
+    let handshake = Handshake::from_bytes(&request)?;
+    if handshake.protocol_str != "BitTorrent protocol" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "peer speaks a different protocol"));
+    }
+    Ok(handshake)
+}
 
-    // expect the next message to be an unchoke message
-    assert!(matches!(
-        PeerMessage::read(&mut stream).await?,
-        PeerMessage::Unchoke
-    ));
-    
+/// Answers the handshake of a peer that connected to us for the torrent with `info_hash`, see `read_handshake`
+pub async fn accept_peer(
+    mut stream: TcpStream,
+    info_hash: &[u8; 20],
+    peer_id: [u8; 20],
+    num_pieces: usize,
+) -> io::Result<PeerConnection> {
+    stream.write_all(&Handshake::new(*info_hash, peer_id).to_bytes_message()).await?;
+    stream.flush().await?;
 
-    Ok(stream)
+    Ok(PeerConnection::new(stream, num_pieces))
 }
 
 impl PeerMessage {
@@ -158,148 +357,376 @@ impl PeerMessage {
     const MSG_ID_PIECE: u8 = 7;
     const MSG_ID_CANCEL: u8 = 8;
 
+    /// Reads one frame, see `PeerCodec` for reading through a buffer
     pub async fn read<R: AsyncRead + Unpin>(reader: &mut R) -> Result<PeerMessage, std::io::Error> {
-        let message_size = reader.read_u32().await?; // Read the length (4 bytes)
-        let message_id = reader.read_u8().await?; // Read the message ID (1 byte)
+        let message_size = reader.read_u32().await? as usize; // Read the length (4 bytes)
+        if message_size > MAX_FRAME_SIZE {
+            return Err(frame_too_large(message_size, MAX_FRAME_SIZE));
+        }
+        let mut payload = vec![0u8; message_size];
+        reader.read_exact(&mut payload).await?;
+        Self::from_payload(&payload)
+    }
 
-        let message = match message_id {
+    pub async fn write<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<(), std::io::Error> {
+        let mut frame = BytesMut::new();
+        self.encode(&mut frame);
+        writer.write_all(&frame).await?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// Parses a frame without its length prefix: the message ID followed by the payload, nothing for a keep-alive.
+    /// Messages we don't know are returned as `Unknown`, known ones must have the right payload length.
+    pub fn from_payload(frame: &[u8]) -> io::Result<PeerMessage> {
+        let Some((&id, payload)) = frame.split_first() else {
+            return Ok(PeerMessage::KeepAlive);
+        };
+        let invalid_len = |expected: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("message {id} has a payload of {} bytes, expected {expected}", payload.len()),
+            )
+        };
+        let expect_len = |len: usize| {
+            if payload.len() == len {
+                Ok(())
+            } else {
+                Err(invalid_len(&len.to_string()))
+            }
+        };
+        let int = |at: usize| u32::from_be_bytes(payload[at..at + 4].try_into().expect("4 bytes"));
+
+        let message = match id {
             Self::MSG_ID_CHOKE => {
+                expect_len(0)?;
                 PeerMessage::Choke
             },
             Self::MSG_ID_UNCHOKE => {
+                expect_len(0)?;
                 PeerMessage::Unchoke
             },
             Self::MSG_ID_INTERESTED => {
+                expect_len(0)?;
                 PeerMessage::Interested
             },
             Self::MSG_ID_NOT_INTERESTED => {
+                expect_len(0)?;
                 PeerMessage::NotInterested
             },
             Self::MSG_ID_HAVE => {
-                let piece_id = reader.read_u32().await?;
-                PeerMessage::Have(piece_id)
+                expect_len(4)?;
+                PeerMessage::Have(int(0))
             }
             Self::MSG_ID_BIT_FIELD => {
                 // Its payload is a bitfield with each index that downloader has sent set to one and the rest set to zero.
                 // Downloaders which don't have anything yet may skip the 'bitfield' message.
                 // The first byte of the bitfield corresponds to indices 0 - 7 from high bit to low bit, respectively.
                 // The next one 8-15, etc. Spare bits at the end are set to zero.
-                let block_length = message_size as usize - 1;
-                let mut block = vec![0u8; block_length];
-                reader.read_exact(&mut block).await?;
-                PeerMessage::Bitfield(block)
+                PeerMessage::Bitfield(payload.to_vec())
             }
             Self::MSG_ID_REQUEST => {
-                let index = reader.read_u32().await?;
-                let begin = reader.read_u32().await?;
-                let length = reader.read_u32().await?;
+                expect_len(12)?;
                 PeerMessage::Request {
-                    index,
-                    begin,
-                    length,
+                    index: int(0),
+                    begin: int(4),
+                    length: int(8),
                 }
             }
             Self::MSG_ID_PIECE => {
-                let index = reader.read_u32().await?;
-                let begin = reader.read_u32().await?;
-                let mut block = vec![0; (message_size - 9) as usize];
-                reader.read_exact(&mut block).await?;
+                if payload.len() < 8 {
+                    return Err(invalid_len("at least 8"));
+                }
                 PeerMessage::Piece {
-                    index,
-                    begin,
-                    block,
+                    index: int(0),
+                    begin: int(4),
+                    block: payload[8..].to_vec(),
                 }
-                // eprintln!("Received piece message");
-                // let index = reader.read_u32().await?;
-                // let begin = reader.read_u32().await?;
-                // let block_length = message_size as usize - 9;
-                // let mut block = vec![0u8; block_length];
-                // eprintln!("Expected block length: {}", block_length);
-
-                // reader.read_exact(&mut block).await?;
-                // PeerMessage::Piece {
-                //     index,
-                //     begin,
-                //     block,
-                // }
             }
             Self::MSG_ID_CANCEL => {
-                let index = reader.read_u32().await?;
-                let begin = reader.read_u32().await?;
-                let length = reader.read_u32().await?;
+                expect_len(12)?;
                 PeerMessage::Cancel {
-                    index,
-                    begin,
-                    length,
+                    index: int(0),
+                    begin: int(4),
+                    length: int(8),
                 }
             }
-            _ => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    "Invalid message ID",
-                ))
-            }
+            _ => PeerMessage::Unknown {
+                id,
+                payload: payload.to_vec(),
+            },
         };
 
         Ok(message)
     }
 
-    pub async fn write<W: AsyncWrite + Unpin>(&self, writer: &mut W) -> Result<(), std::io::Error> {
-        match &self {
-            PeerMessage::Choke => {
-                writer.write_u32(1).await?;
-                writer.write_u8(Self::MSG_ID_CHOKE).await?;
-                eprintln!("Sent Choke message");
-            }
-            PeerMessage::Unchoke => {
-                writer.write_u32(1).await?;
-                writer.write_u8(Self::MSG_ID_UNCHOKE).await?;
-            }
-            PeerMessage::Interested => {
-                writer.write_u32(1).await?;
-                writer.write_u8(Self::MSG_ID_INTERESTED).await?;
-            }
-            PeerMessage::NotInterested => {
-                writer.write_u32(1).await?;
-                writer.write_u8(Self::MSG_ID_NOT_INTERESTED).await?;
-            }
+    /// Appends the message to `buf` as a frame, length prefix included
+    pub fn encode(&self, buf: &mut BytesMut) {
+        let start = buf.len();
+        buf.put_u32(0); // length, filled in below
+        match self {
+            PeerMessage::KeepAlive => {}
+            PeerMessage::Choke => buf.put_u8(Self::MSG_ID_CHOKE),
+            PeerMessage::Unchoke => buf.put_u8(Self::MSG_ID_UNCHOKE),
+            PeerMessage::Interested => buf.put_u8(Self::MSG_ID_INTERESTED),
+            PeerMessage::NotInterested => buf.put_u8(Self::MSG_ID_NOT_INTERESTED),
             PeerMessage::Have(piece_id) => {
-                writer.write_u32(5).await?;
-                writer.write_u8(Self::MSG_ID_HAVE).await?;
-                writer.write_u32(*piece_id).await?;
+                buf.put_u8(Self::MSG_ID_HAVE);
+                buf.put_u32(*piece_id);
             }
             PeerMessage::Bitfield(block) => {
-                writer.write_u32((block.len() + 1) as u32).await?;
-                writer.write_u8(Self::MSG_ID_BIT_FIELD).await?;
-                writer.write_all(&block).await?;
+                buf.put_u8(Self::MSG_ID_BIT_FIELD);
+                buf.put_slice(block);
             }
             PeerMessage::Request { index, begin, length } => {
-                writer.write_u32(13).await?;
-                writer.write_u8(Self::MSG_ID_REQUEST).await?;
-                writer.write_u32(*index).await?;
-                writer.write_u32(*begin).await?;
-                writer.write_u32(*length).await?;
+                buf.put_u8(Self::MSG_ID_REQUEST);
+                buf.put_u32(*index);
+                buf.put_u32(*begin);
+                buf.put_u32(*length);
             }
             PeerMessage::Piece { index, begin, block } => {
-                writer.write_u32((block.len() + 9) as u32).await?;
-                writer.write_u8(Self::MSG_ID_PIECE).await?;
-                writer.write_u32(*index).await?;
-                writer.write_u32(*begin).await?;
-                writer.write_all(&block).await?;
+                buf.put_u8(Self::MSG_ID_PIECE);
+                buf.put_u32(*index);
+                buf.put_u32(*begin);
+                buf.put_slice(block);
             }
             PeerMessage::Cancel { index, begin, length } => {
-                writer.write_u32(13).await?;
-                writer.write_u8(Self::MSG_ID_CANCEL).await?;
-                writer.write_u32(*index).await?;
-                writer.write_u32(*begin).await?;
-                writer.write_u32(*length).await?;
+                buf.put_u8(Self::MSG_ID_CANCEL);
+                buf.put_u32(*index);
+                buf.put_u32(*begin);
+                buf.put_u32(*length);
             }
+            PeerMessage::Unknown { id, payload } => {
+                buf.put_u8(*id);
+                buf.put_slice(payload);
+            }
+        }
+        let len = (buf.len() - start - 4) as u32;
+        buf[start..start + 4].copy_from_slice(&len.to_be_bytes());
+    }
+}
+
+fn frame_too_large(size: usize, max: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("peer sent a frame of {size} bytes, the limit is {max}"),
+    )
+}
+
+/// Splits a byte stream into peer messages, in the style of a `tokio_util` codec:
+/// `decode` takes a complete frame off the front of a buffer once it is all there,
+/// so nothing is allocated for a frame before its length is checked against the limit.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerCodec {
+    max_frame_size: usize,
+}
+
+impl Default for PeerCodec {
+    fn default() -> Self {
+        PeerCodec::new(MAX_FRAME_SIZE)
+    }
+}
+
+impl PeerCodec {
+    pub fn new(max_frame_size: usize) -> Self {
+        PeerCodec { max_frame_size }
+    }
+
+    /// The next message in `buf`, `None` if more bytes are needed
+    pub fn decode(&self, buf: &mut BytesMut) -> io::Result<Option<PeerMessage>> {
+        let Some(prefix) = buf.get(..4) else {
+            return Ok(None);
         };
+        let size = u32::from_be_bytes(prefix.try_into().expect("4 bytes")) as usize;
+        if size > self.max_frame_size {
+            return Err(frame_too_large(size, self.max_frame_size));
+        }
+        if buf.len() < 4 + size {
+            buf.reserve(4 + size - buf.len());
+            return Ok(None);
+        }
+        buf.advance(4);
+        let frame = buf.split_to(size);
+        PeerMessage::from_payload(&frame).map(Some)
+    }
 
-        writer.flush().await?;
-        Ok(())
+    pub fn encode(&self, message: &PeerMessage, buf: &mut BytesMut) {
+        message.encode(buf);
+    }
 
+    /// Reads from `reader` into `buf` until a whole message is there, this is cancel safe
+    pub async fn read<R: AsyncRead + Unpin>(&self, reader: &mut R, buf: &mut BytesMut) -> io::Result<PeerMessage> {
+        loop {
+            if let Some(message) = self.decode(buf)? {
+                return Ok(message);
+            }
+            if reader.read_buf(buf).await? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    /// Both ends of a loopback TCP connection
+    async fn sockets() -> (TcpStream, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ours = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let (theirs, _) = listener.accept().await.unwrap();
+        (ours, theirs)
+    }
+
+    /// A connection to a peer with `num_pieces` pieces, and the peer's end of it
+    async fn pair(num_pieces: usize) -> (PeerConnection, TcpStream) {
+        let (ours, theirs) = sockets().await;
+        (PeerConnection::new(ours, num_pieces), theirs)
+    }
 
+    #[tokio::test]
+    async fn have_must_name_an_existing_piece() {
+        let (mut connection, mut peer) = pair(10).await;
+        PeerMessage::Have(9).write(&mut peer).await.unwrap();
+        assert!(matches!(connection.recv().await.unwrap(), PeerMessage::Have(9)));
+        assert!(connection.bitfield.has(9));
+
+        // inside the spare bits of the last byte, but there is no piece 12
+        PeerMessage::Have(12).write(&mut peer).await.unwrap();
+        assert_eq!(connection.recv().await.unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert!(!connection.bitfield.has(12));
+    }
+
+    fn frame(bytes: &[u8]) -> BytesMut {
+        BytesMut::from(bytes)
+    }
+
+    #[test]
+    fn rejects_a_frame_over_the_limit_before_allocating_it() {
+        let codec = PeerCodec::new(1024);
+        let mut buf = BytesMut::with_capacity(8);
+        buf.put_u32(1025);
+        buf.put_u8(PeerMessage::MSG_ID_PIECE);
+        let capacity = buf.capacity();
+        assert_eq!(codec.decode(&mut buf).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        assert_eq!(buf.capacity(), capacity);
+
+        let mut buf = frame(&[0xff, 0xff, 0xff, 0xff]);
+        assert!(PeerCodec::default().decode(&mut buf).is_err());
+        // a frame right at the limit is fine
+        let mut buf = frame(&[0, 0, 0, 4, 9, 1, 2, 3]);
+        assert!(PeerCodec::new(4).decode(&mut buf).unwrap().is_some());
+    }
+
+    #[test]
+    fn checks_the_payload_length_of_known_messages() {
+        for payload in [&[7u8][..], &[7, 0, 0, 0, 1, 0, 0, 0]] {
+            assert!(PeerMessage::from_payload(payload).is_err(), "piece of {} bytes", payload.len());
+        }
+        assert_eq!(
+            PeerMessage::from_payload(&[7, 0, 0, 0, 1, 0, 0, 0, 2]).unwrap(),
+            PeerMessage::Piece { index: 1, begin: 2, block: Vec::new() }
+        );
+        let wrong_lengths = [
+            (PeerMessage::MSG_ID_HAVE, [0, 3, 5, 12]),
+            (PeerMessage::MSG_ID_REQUEST, [0, 4, 11, 13]),
+            (PeerMessage::MSG_ID_CANCEL, [0, 4, 11, 13]),
+        ];
+        for (id, lengths) in wrong_lengths {
+            for len in lengths {
+                let payload = [&[id][..], &vec![0; len]].concat();
+                assert!(PeerMessage::from_payload(&payload).is_err(), "message {id} with {len} bytes");
+            }
+        }
+        assert!(PeerMessage::from_payload(&[PeerMessage::MSG_ID_CHOKE, 0]).is_err());
+    }
+
+    #[test]
+    fn returns_unknown_messages() {
+        // e.g. a BEP 6 Have All
+        assert_eq!(
+            PeerMessage::from_payload(&[0x0e]).unwrap(),
+            PeerMessage::Unknown { id: 0x0e, payload: Vec::new() }
+        );
+        assert_eq!(
+            PeerMessage::from_payload(&[20, 0, b'd', b'e']).unwrap(),
+            PeerMessage::Unknown { id: 20, payload: vec![0, b'd', b'e'] }
+        );
+    }
+
+    #[test]
+    fn decodes_frames_split_across_reads() {
+        let codec = PeerCodec::default();
+        let mut encoded = BytesMut::new();
+        codec.encode(&PeerMessage::Request { index: 1, begin: 2, length: 3 }, &mut encoded);
+        codec.encode(&PeerMessage::Have(7), &mut encoded);
+
+        let mut buf = BytesMut::new();
+        let mut decoded = Vec::new();
+        for byte in encoded.iter() {
+            buf.put_u8(*byte);
+            if let Some(message) = codec.decode(&mut buf).unwrap() {
+                decoded.push(message);
+            }
+        }
+        assert_eq!(decoded, vec![PeerMessage::Request { index: 1, begin: 2, length: 3 }, PeerMessage::Have(7)]);
+        assert!(buf.is_empty());
+    }
+
+    #[tokio::test]
+    async fn reads_a_frame_that_arrives_in_pieces() {
+        let (mut reader, mut writer) = tokio::io::duplex(64);
+        let message = PeerMessage::Piece { index: 3, begin: 16384, block: vec![5; 40] };
+        let mut encoded = BytesMut::new();
+        message.encode(&mut encoded);
+        tokio::spawn(async move {
+            for chunk in encoded.chunks(7) {
+                writer.write_all(chunk).await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        let mut buf = BytesMut::new();
+        assert_eq!(PeerCodec::default().read(&mut reader, &mut buf).await.unwrap(), message);
+        assert_eq!(PeerCodec::default().read(&mut reader, &mut buf).await.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn keep_alive_is_an_empty_frame() {
+        let mut buf = BytesMut::new();
+        PeerMessage::KeepAlive.encode(&mut buf);
+        assert_eq!(&buf[..], [0, 0, 0, 0]);
+        assert_eq!(PeerCodec::default().decode(&mut buf).unwrap(), Some(PeerMessage::KeepAlive));
+        assert_eq!(PeerMessage::from_payload(&[]).unwrap(), PeerMessage::KeepAlive);
+    }
+
+    /// A connection with short keep-alive and idle timeouts, and the peer's end of it
+    async fn impatient_pair(keep_alive_interval: Duration, idle_timeout: Duration) -> (PeerConnection, TcpStream) {
+        let (ours, theirs) = sockets().await;
+        (PeerConnection::with_timeouts(ours, 1, keep_alive_interval, idle_timeout), theirs)
+    }
+
+    #[tokio::test]
+    async fn sends_keep_alives_while_we_have_nothing_to_say() {
+        let (connection, mut peer) = impatient_pair(Duration::from_millis(50), Duration::from_secs(60)).await;
+        for _ in 0..3 {
+            let message = tokio::time::timeout(Duration::from_secs(5), PeerMessage::read(&mut peer)).await;
+            assert_eq!(message.expect("no keep-alive").unwrap(), PeerMessage::KeepAlive);
+        }
+        drop(connection);
+    }
+
+    #[tokio::test]
+    async fn drops_a_peer_that_stays_silent() {
+        let (mut connection, mut peer) = impatient_pair(Duration::from_secs(60), Duration::from_millis(300)).await;
+        // keep-alives from the peer keep the connection open past the timeout
+        for _ in 0..4 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            PeerMessage::KeepAlive.write(&mut peer).await.unwrap();
+            assert_eq!(connection.recv().await.unwrap(), PeerMessage::KeepAlive);
+        }
+        let error = tokio::time::timeout(Duration::from_secs(5), connection.recv()).await.expect("still waiting");
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}
@@ -0,0 +1,210 @@
+use rand::seq::SliceRandom;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use crate::bitfield::Bitfield;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PieceState {
+    Missing,
+    /// handed out to a peer and not finished yet
+    InFlight,
+    Done,
+}
+
+/// Decides which piece to download next from which peer.
+/// Tracks how many connected peers have each piece and hands out the rarest pieces first,
+/// so pieces that only a few peers have are fetched while those peers are still around.
+#[derive(Debug, Clone)]
+pub struct PiecePicker {
+    /// number of connected peers that have each piece
+    availability: Vec<u32>,
+    state: Vec<PieceState>,
+    peers: HashMap<SocketAddr, Bitfield>,
+    remaining: usize,
+    missing: usize,
+}
+
+impl PiecePicker {
+    pub fn new(num_pieces: usize) -> Self {
+        PiecePicker {
+            availability: vec![0; num_pieces],
+            state: vec![PieceState::Missing; num_pieces],
+            peers: HashMap::new(),
+            remaining: num_pieces,
+            missing: num_pieces,
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.state.len()
+    }
+
+    /// Number of pieces not downloaded yet, including the ones in flight
+    pub fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Number of pieces nobody started on yet, not counting the ones in flight
+    pub fn missing(&self) -> usize {
+        self.missing
+    }
+
+    pub fn availability(&self, index: u32) -> u32 {
+        self.availability[index as usize]
+    }
+
+    /// Records what `peer` has, replacing anything known about it before.
+    /// Returns true if the peer now has a piece it didn't have before.
+    /// Bits beyond the last piece are ignored.
+    pub fn update_peer(&mut self, peer: SocketAddr, bitfield: &Bitfield) -> bool {
+        let old = self.peers.remove(&peer).unwrap_or_default();
+        let mut gained = false;
+        for index in 0..self.num_pieces() as u32 {
+            match (old.has(index), bitfield.has(index)) {
+                (false, true) => {
+                    self.availability[index as usize] += 1;
+                    gained = true;
+                }
+                (true, false) => self.availability[index as usize] -= 1,
+                _ => {}
+            }
+        }
+        self.peers.insert(peer, bitfield.clone());
+        gained
+    }
+
+    /// Records a Have message, returns true if the piece is new for this peer
+    pub fn peer_has(&mut self, peer: SocketAddr, index: u32) -> bool {
+        if index as usize >= self.num_pieces() {
+            return false;
+        }
+        let num_pieces = self.num_pieces();
+        let bitfield = self.peers.entry(peer).or_insert_with(|| Bitfield::new(num_pieces));
+        if bitfield.has(index) {
+            return false;
+        }
+        bitfield.set(index);
+        self.availability[index as usize] += 1;
+        true
+    }
+
+    /// Whether `peer` announced the piece at `index`
+    pub fn peer_has_piece(&self, peer: SocketAddr, index: u32) -> bool {
+        self.peers.get(&peer).is_some_and(|bitfield| bitfield.has(index))
+    }
+
+    /// Whether `peer` has a piece we don't have yet
+    pub fn is_interesting(&self, peer: SocketAddr) -> bool {
+        self.peers.get(&peer).is_some_and(|bitfield| {
+            bitfield
+                .pieces()
+                .any(|i| (i as usize) < self.num_pieces() && self.state[i as usize] != PieceState::Done)
+        })
+    }
+
+    /// The connected peers that have the piece at `index`
+    pub fn peers_with(&self, index: u32) -> impl Iterator<Item = SocketAddr> + '_ {
+        self.peers
+            .iter()
+            .filter(move |(_, bitfield)| bitfield.has(index))
+            .map(|(&peer, _)| peer)
+    }
+
+    /// Forgets a disconnected peer
+    pub fn remove_peer(&mut self, peer: SocketAddr) {
+        if let Some(bitfield) = self.peers.remove(&peer) {
+            let num_pieces = self.num_pieces() as u32;
+            for index in bitfield.pieces().take_while(|&i| i < num_pieces) {
+                self.availability[index as usize] -= 1;
+            }
+        }
+    }
+
+    /// Picks the rarest missing piece that `peer` has and marks it in flight.
+    /// Ties are broken at random so peers don't all start on the same piece.
+    pub fn pick(&mut self, peer: SocketAddr) -> Option<u32> {
+        let index = self.choose(peer, |_| true)?;
+        self.start(index);
+        Some(index)
+    }
+
+    /// The piece `pick` would hand out, only considering pieces for which `allowed` returns true.
+    /// Nothing is marked in flight, use `start` for the piece that is actually requested.
+    pub fn choose(&self, peer: SocketAddr, allowed: impl Fn(u32) -> bool) -> Option<u32> {
+        let bitfield = self.peers.get(&peer)?;
+        let candidates: Vec<u32> = (0..self.num_pieces() as u32)
+            .filter(|&i| self.state[i as usize] == PieceState::Missing && bitfield.has(i) && allowed(i))
+            .collect();
+        let rarest = candidates.iter().map(|&i| self.availability[i as usize]).min()?;
+        let rarest: Vec<u32> = candidates
+            .into_iter()
+            .filter(|&i| self.availability[i as usize] == rarest)
+            .collect();
+        rarest.choose(&mut rand::thread_rng()).copied()
+    }
+
+    /// Marks a missing piece as in flight
+    pub fn start(&mut self, index: u32) {
+        if self.state[index as usize] == PieceState::Missing {
+            self.state[index as usize] = PieceState::InFlight;
+            self.missing -= 1;
+        }
+    }
+
+    /// Makes an in flight piece available to be picked again
+    pub fn release(&mut self, index: u32) {
+        if self.state[index as usize] == PieceState::InFlight {
+            self.state[index as usize] = PieceState::Missing;
+            self.missing += 1;
+        }
+    }
+
+    pub fn complete(&mut self, index: u32) {
+        if self.state[index as usize] == PieceState::Missing {
+            self.missing -= 1;
+        }
+        if self.state[index as usize] != PieceState::Done {
+            self.state[index as usize] = PieceState::Done;
+            self.remaining -= 1;
+        }
+    }
+
+    pub fn is_done(&self, index: u32) -> bool {
+        self.state[index as usize] == PieceState::Done
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_counts_pieces_nobody_started() {
+        let mut picker = PiecePicker::new(4);
+        assert_eq!(picker.missing(), 4);
+        picker.start(0);
+        picker.start(0);
+        assert_eq!(picker.missing(), 3);
+        picker.release(0);
+        assert_eq!(picker.missing(), 4);
+        picker.start(1);
+        picker.complete(1);
+        picker.complete(2);
+        assert_eq!((picker.missing(), picker.remaining()), (2, 2));
+        picker.complete(2);
+        assert_eq!((picker.missing(), picker.remaining()), (2, 2));
+    }
+
+    #[test]
+    fn peer_has_counts_each_piece_of_a_peer_once() {
+        let mut picker = PiecePicker::new(4);
+        let peer = SocketAddr::from(([10, 0, 0, 1], 6881));
+        assert!(picker.peer_has(peer, 2));
+        assert!(!picker.peer_has(peer, 2));
+        assert!(!picker.peer_has(peer, 4));
+        assert_eq!(picker.availability(2), 1);
+        assert!(picker.peer_has_piece(peer, 2));
+        picker.remove_peer(peer);
+        assert_eq!(picker.availability(2), 0);
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use crate::bencode::{self, Value};
+use crate::bitfield::Bitfield;
+use crate::storage::Storage;
+use crate::torrent::Info;
+use crate::verify;
+
+/// Progress of a download: which pieces are verified on disk, for which torrent.
+/// Stored bencoded as `d9:info hash20:...6:pieces<bitfield>e`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResumeState {
+    pub info_hash: [u8; 20],
+    pub have: Bitfield,
+}
+
+impl ResumeState {
+    pub fn new(info_hash: [u8; 20], num_pieces: usize) -> Self {
+        ResumeState {
+            info_hash,
+            have: Bitfield::new(num_pieces),
+        }
+    }
+
+    /// The resume file that goes with a download to `output`, e.g. `sample.txt.resume`
+    pub fn path_for(output: &Path) -> PathBuf {
+        let mut path = OsString::from(output.as_os_str());
+        path.push(".resume");
+        PathBuf::from(path)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut dict = BTreeMap::new();
+        dict.insert(b"info hash".to_vec(), Value::from(&self.info_hash[..]));
+        dict.insert(b"pieces".to_vec(), Value::from(self.have.as_bytes()));
+        Value::Dict(dict).encode()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: &str| io::Error::new(io::ErrorKind::InvalidData, format!("resume file: {msg}"));
+        let value = bencode::decode(bytes).map_err(|e| invalid(&e.to_string()))?;
+        let info_hash = value
+            .get(b"info hash")
+            .and_then(Value::as_bytes)
+            .and_then(|b| <[u8; 20]>::try_from(b).ok())
+            .ok_or_else(|| invalid("missing info hash"))?;
+        let have = value
+            .get(b"pieces")
+            .and_then(Value::as_bytes)
+            .ok_or_else(|| invalid("missing pieces"))?;
+        Ok(ResumeState {
+            info_hash,
+            have: Bitfield::from_bytes(have.to_vec()),
+        })
+    }
+
+    /// Reads a resume file, `None` if there is none
+    pub fn load(path: &Path) -> io::Result<Option<Self>> {
+        match std::fs::read(path) {
+            Ok(bytes) => Self::from_bytes(&bytes).map(Some),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes the state to a temporary file first and renames it over `path`,
+    /// so an interrupted save leaves the previous state intact
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut tmp = OsString::from(path.as_os_str());
+        tmp.push(".tmp");
+        std::fs::write(&tmp, self.to_bytes())?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Re-hashes the pieces the state claims to have and drops the ones that don't match,
+    /// e.g. because the output files were changed since. Blocks while reading.
+    pub fn recheck(&mut self, storage: &Storage, info: &Info) {
+        for index in self.have.pieces().collect::<Vec<_>>() {
+            if !verify::check_piece(storage, info, index) {
+                self.have.clear(index);
+            }
+        }
+    }
+}
+
+/// A resume file that is kept up to date while downloading
+#[derive(Debug)]
+pub struct ResumeFile {
+    path: PathBuf,
+    state: Mutex<ResumeState>,
+}
+
+impl ResumeFile {
+    pub fn new(path: PathBuf, state: ResumeState) -> Self {
+        ResumeFile {
+            path,
+            state: Mutex::new(state),
+        }
+    }
+
+    pub fn have(&self) -> Bitfield {
+        self.state.lock().expect("resume lock poisoned").have.clone()
+    }
+
+    /// Records a verified piece and saves the file. Blocks while writing.
+    pub fn piece_done(&self, piece_index: u32) -> io::Result<()> {
+        let mut state = self.state.lock().expect("resume lock poisoned");
+        state.have.set(piece_index);
+        state.save(&self.path)
+    }
+
+    /// Deletes the resume file once the download is complete
+    pub fn remove(&self) -> io::Result<()> {
+        match std::fs::remove_file(&self.path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{Hashes, Keys};
+    use sha1::{Digest, Sha1};
+
+    #[test]
+    fn round_trips_through_bytes() {
+        let mut state = ResumeState::new([7; 20], 10);
+        state.have.set(0);
+        state.have.set(9);
+        let bytes = state.to_bytes();
+        assert_eq!(ResumeState::from_bytes(&bytes).unwrap(), state);
+
+        for damaged in [&bytes[..bytes.len() - 1], b"", b"d6:piecesi1ee", b"d9:info hash3:abc6:pieces0:e"] {
+            assert_eq!(ResumeState::from_bytes(damaged).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn saves_and_loads_next_to_the_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = ResumeState::path_for(&dir.path().join("out.iso"));
+        assert_eq!(path, dir.path().join("out.iso.resume"));
+        assert_eq!(ResumeState::load(&path).unwrap(), None);
+
+        let mut state = ResumeState::new([7; 20], 3);
+        state.have.set(1);
+        state.save(&path).unwrap();
+        assert_eq!(ResumeState::load(&path).unwrap(), Some(state));
+    }
+
+    #[test]
+    fn recheck_drops_pieces_that_changed_on_disk() {
+        let data: Vec<u8> = (0..48u32).map(|i| i as u8).collect();
+        let info = Info {
+            name: "out".to_string(),
+            plength: 16,
+            pieces: Hashes(data.chunks(16).map(|piece| Sha1::digest(piece).into()).collect()),
+            keys: Keys::SingleFile { length: data.len() as u64 },
+        };
+        let dir = tempfile::tempdir().unwrap();
+        let storage = Storage::create(&dir.path().join("out"), &info).unwrap();
+        storage.write_piece(0, &data[..16]).unwrap();
+        storage.write_piece(1, &[0; 16]).unwrap();
+
+        // claims every piece, but piece 1 was overwritten and piece 2 was never written
+        let mut state = ResumeState::new([7; 20], 3);
+        state.have = Bitfield::full(3);
+        state.recheck(&storage, &info);
+        assert_eq!(state.have.pieces().collect::<Vec<_>>(), vec![0]);
+    }
+}
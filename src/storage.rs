@@ -0,0 +1,150 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use crate::torrent::{FileMap, Info, Keys};
+
+/// The files of a torrent on disk.
+/// Pieces are read and written in place, so only the pieces being worked on need to be in memory.
+/// The methods block, call them through `tokio::task::spawn_blocking` from async code.
+#[derive(Debug)]
+pub struct Storage {
+    file_map: FileMap,
+    paths: Vec<PathBuf>,
+    /// `None` for files that don't exist, reading from them fails
+    files: Vec<Option<Mutex<File>>>,
+    piece_length: u32,
+    length: u64,
+}
+
+impl Storage {
+    /// Where the files of a torrent go below `output`.
+    /// A single file torrent is stored at `output` itself,
+    /// a multi file torrent becomes the directory tree `output/<name>/<path>...`
+    pub fn paths(output: &Path, info: &Info) -> Vec<PathBuf> {
+        match info.keys {
+            Keys::SingleFile { .. } => vec![output.to_path_buf()],
+            Keys::MultiFile { .. } => FileMap::new(info)
+                .files
+                .iter()
+                .map(|file| output.join(&file.path))
+                .collect(),
+        }
+    }
+
+    /// Creates the output files, or opens them if they already exist, and sets them to their final length.
+    pub fn create(output: &Path, info: &Info) -> io::Result<Self> {
+        let file_map = FileMap::new(info);
+        let paths = Self::paths(output, info);
+        let mut files = Vec::with_capacity(paths.len());
+        for (path, entry) in paths.iter().zip(&file_map.files) {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)?;
+            file.set_len(entry.length)?;
+            files.push(Some(Mutex::new(file)));
+        }
+        Ok(Storage {
+            file_map,
+            paths,
+            files,
+            piece_length: info.plength,
+            length: info.length(),
+        })
+    }
+
+    /// Opens existing files read only, e.g. to check or serve them.
+    /// Files that are missing or too short fail when the pieces they hold are read.
+    pub fn open(output: &Path, info: &Info) -> io::Result<Self> {
+        let file_map = FileMap::new(info);
+        let paths = Self::paths(output, info);
+        let mut files = Vec::with_capacity(paths.len());
+        for path in &paths {
+            match File::open(path) {
+                Ok(file) => files.push(Some(Mutex::new(file))),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => files.push(None),
+                Err(e) => return Err(io::Error::new(e.kind(), format!("open {}: {e}", path.display()))),
+            }
+        }
+        Ok(Storage {
+            file_map,
+            paths,
+            files,
+            piece_length: info.plength,
+            length: info.length(),
+        })
+    }
+
+    pub fn file_map(&self) -> &FileMap {
+        &self.file_map
+    }
+
+    /// The paths of the files on disk, in the order of `FileMap::files`
+    pub fn file_paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    fn file(&self, file_index: usize) -> io::Result<MutexGuard<'_, File>> {
+        match &self.files[file_index] {
+            Some(file) => Ok(file.lock().expect("file lock poisoned")),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not exist", self.paths[file_index].display()),
+            )),
+        }
+    }
+
+    fn piece_size(&self, piece_index: u32) -> u32 {
+        let remaining = self.length.saturating_sub(piece_index as u64 * self.piece_length as u64);
+        std::cmp::min(self.piece_length as u64, remaining) as u32
+    }
+
+    /// Writes a whole piece to the file(s) it belongs to
+    pub fn write_piece(&self, piece_index: u32, data: &[u8]) -> io::Result<()> {
+        if data.len() != self.piece_size(piece_index) as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "piece has the wrong size"));
+        }
+        for span in self.file_map.piece_spans(piece_index, data.len() as u32) {
+            let start = span.piece_offset as usize;
+            let mut file = self.file(span.file_index)?;
+            file.seek(SeekFrom::Start(span.file_offset))?;
+            file.write_all(&data[start..start + span.len as usize])?;
+        }
+        Ok(())
+    }
+
+    /// Reads `length` bytes starting at `begin` inside the piece at `piece_index`
+    pub fn read_block(&self, piece_index: u32, begin: u32, length: u32) -> io::Result<Vec<u8>> {
+        let piece_size = self.piece_size(piece_index);
+        if begin.checked_add(length).map_or(true, |end| end > piece_size) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "block is outside of the piece"));
+        }
+        let offset = piece_index as u64 * self.piece_length as u64 + begin as u64;
+        let mut data = vec![0; length as usize];
+        for span in self.file_map.spans(offset, length) {
+            let start = span.piece_offset as usize;
+            let mut file = self.file(span.file_index)?;
+            file.seek(SeekFrom::Start(span.file_offset))?;
+            file.read_exact(&mut data[start..start + span.len as usize])?;
+        }
+        Ok(data)
+    }
+
+    pub fn read_piece(&self, piece_index: u32) -> io::Result<Vec<u8>> {
+        self.read_block(piece_index, 0, self.piece_size(piece_index))
+    }
+
+    /// Makes sure everything written so far reached the disk
+    pub fn flush(&self) -> io::Result<()> {
+        for file in self.files.iter().flatten() {
+            file.lock().expect("file lock poisoned").sync_data()?;
+        }
+        Ok(())
+    }
+}
@@ -1,37 +1,67 @@
+use anyhow::Context;
 use serde::{Deserialize, Serialize};
 use sha1::{Sha1, Digest};
+use std::path::{Path, PathBuf};
+use crate::bencode;
 
 pub use hashes::Hashes;
 
 /// A Metainfo files (also known as .torrent files)
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Torrent {
     /// URL to a "tracker", which is a central server that keeps track of peers participating in the sharing of a torrent.
     pub announce: String,
 
     pub info: Info,
+
+    /// SHA-1 of the info dictionary, worked out once when the torrent is created
+    #[serde(skip)]
+    info_hash: [u8; 20],
+}
+
+/// The parts of a .torrent file we read, `Torrent` adds the info hash
+#[derive(Deserialize)]
+struct MetaInfo {
+    announce: String,
+    info: Info,
 }
 
 impl Torrent {
+    /// Parses a .torrent file. The info hash is taken over the info dictionary exactly as it appears in the file,
+    /// so keys we don't model (private, source, ...) are included.
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        let MetaInfo { announce, info } = serde_bencode::from_bytes(bytes).context("parse torrent file")?;
+        info.validate().context("invalid torrent file")?;
+        let info_bytes = bencode::dict_entry(bytes, b"info")
+            .context("locate info dictionary")?
+            .context("torrent file has no info dictionary")?;
+        Ok(Torrent {
+            announce,
+            info,
+            info_hash: Sha1::digest(info_bytes).into(),
+        })
+    }
+
+    /// A torrent built in code. Its info hash is taken over `info` encoded canonically,
+    /// which matches a .torrent file only if the file's info dictionary has no keys we don't model.
+    pub fn from_info(announce: String, info: Info) -> anyhow::Result<Self> {
+        let info_encoded = bencode::to_bytes(&info).context("encode info dictionary")?;
+        Ok(Torrent {
+            announce,
+            info,
+            info_hash: Sha1::digest(info_encoded).into(),
+        })
+    }
+
+    pub fn read(path: impl AsRef<Path>) -> anyhow::Result<Self> {
+        let dot_torrent = std::fs::read(path).context("read torrent file")?;
+        Self::from_bytes(&dot_torrent)
+    }
+
+    /// SHA-1 of the bencoded info dictionary
     pub fn info_hash(&self) -> [u8; 20] {
-        let info_encoded =
-            serde_bencode::to_bytes(&self.info).expect("re-encode info section should be fine");
-        let mut hasher = Sha1::new();
-        hasher.update(&info_encoded);
-        hasher
-            .finalize()
-            .try_into()
-            .expect("GenericArray<_, 20> == [_; 20]")
-    }
-    // pub fn info_hash(&self) -> [u8; 20] {
-    //     let info_dict_bytes = serde_bencode::to_bytes(&self.info).expect("re-encode info section should be fine");
-    //     let mut hasher = Sha1::new();
-    //     hasher.update(&info_dict_bytes);
-    //     let info_hash =  hasher.finalize();
-    //     info_hash
-    //         .try_into()
-    //         .expect("GenericArray should be able to be converted")
-    // }
+        self.info_hash
+    }
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -65,7 +95,7 @@ pub struct Info {
 #[serde(untagged)]
 pub enum Keys {
     SingleFile {
-        length: u32,
+        length: u64,
     },
     MultiFile { 
         files: Vec<File> 
@@ -75,12 +105,149 @@ pub enum Keys {
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct File {
     // the length of the file, in bytes
-    pub length: u32,
+    pub length: u64,
 
     // Subdirectory names for this file
     pub path:Vec<String>,
 }
 
+impl Info {
+    /// Total number of bytes in the torrent, summed over all files for a multi file torrent
+    pub fn length(&self) -> u64 {
+        match &self.keys {
+            Keys::SingleFile { length } => *length,
+            Keys::MultiFile { files } => files.iter().map(|f| f.length).sum(),
+        }
+    }
+
+    pub fn num_pieces(&self) -> usize {
+        self.pieces.0.len()
+    }
+
+    /// Size of the piece at `piece_index`, only the last piece may be shorter than `plength`
+    pub fn piece_size(&self, piece_index: u32) -> u32 {
+        let remaining = self.length().saturating_sub(self.piece_offset(piece_index));
+        std::cmp::min(self.plength as u64, remaining) as u32
+    }
+
+    /// Offset of the first byte of the piece at `piece_index` in the torrent
+    pub fn piece_offset(&self, piece_index: u32) -> u64 {
+        piece_index as u64 * self.plength as u64
+    }
+
+    /// Checks what serde can't: the piece hashes cover the length and file paths stay inside the download directory.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(self.plength > 0, "piece length is zero");
+        let expected_pieces = self.length().div_ceil(self.plength as u64);
+        anyhow::ensure!(
+            self.num_pieces() as u64 == expected_pieces,
+            "expected {expected_pieces} piece hashes for {} bytes, found {}",
+            self.length(),
+            self.num_pieces()
+        );
+        let mut names = vec![&self.name];
+        if let Keys::MultiFile { files } = &self.keys {
+            anyhow::ensure!(!files.is_empty(), "multi file torrent has no files");
+            for file in files {
+                anyhow::ensure!(!file.path.is_empty(), "file with an empty path");
+                names.extend(&file.path);
+            }
+        }
+        for name in names {
+            anyhow::ensure!(
+                !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\']),
+                "unsafe path component {name:?}"
+            );
+        }
+        Ok(())
+    }
+}
+
+/// A file of the torrent, placed in the byte stream that the pieces are cut from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// Path relative to the download directory,
+    /// `name` for a single file torrent and `name/path...` for a multi file torrent
+    pub path: PathBuf,
+    pub length: u64,
+    /// Offset of the first byte of this file in the torrent
+    pub offset: u64,
+}
+
+/// The part of a piece that lives in a single file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FileSpan {
+    /// Index into `FileMap::files`
+    pub file_index: usize,
+    /// Where the span starts inside the file
+    pub file_offset: u64,
+    /// Where the span starts inside the piece
+    pub piece_offset: u32,
+    pub len: u32,
+}
+
+/// Maps pieces to the files they are stored in.
+#[derive(Debug, Clone)]
+pub struct FileMap {
+    pub files: Vec<FileEntry>,
+    piece_length: u64,
+}
+
+impl FileMap {
+    pub fn new(info: &Info) -> Self {
+        let files = match &info.keys {
+            Keys::SingleFile { length } => vec![FileEntry {
+                path: PathBuf::from(&info.name),
+                length: *length,
+                offset: 0,
+            }],
+            Keys::MultiFile { files } => {
+                let mut offset = 0;
+                files
+                    .iter()
+                    .map(|f| {
+                        let mut path = PathBuf::from(&info.name);
+                        path.extend(&f.path);
+                        let entry = FileEntry { path, length: f.length, offset };
+                        offset += f.length;
+                        entry
+                    })
+                    .collect()
+            }
+        };
+        FileMap { files, piece_length: info.plength as u64 }
+    }
+
+    /// Splits `len` bytes starting at torrent offset `offset` into per file spans.
+    /// `piece_offset` of each span is relative to `offset`. Empty files never produce a span.
+    pub fn spans(&self, offset: u64, len: u32) -> Vec<FileSpan> {
+        let end = offset + len as u64;
+        // the first file that ends after `offset`
+        let first = self.files.partition_point(|f| f.offset + f.length <= offset);
+        self.files[first..]
+            .iter()
+            .enumerate()
+            .take_while(|(_, f)| f.offset < end)
+            .filter(|(_, f)| f.length > 0)
+            .map(|(i, f)| {
+                let start = std::cmp::max(offset, f.offset);
+                let stop = std::cmp::min(end, f.offset + f.length);
+                FileSpan {
+                    file_index: first + i,
+                    file_offset: start - f.offset,
+                    piece_offset: (start - offset) as u32,
+                    len: (stop - start) as u32,
+                }
+            })
+            .collect()
+    }
+
+    /// The spans covering the piece at `piece_index`, `piece_size` is usually `Info::piece_size`
+    pub fn piece_spans(&self, piece_index: u32, piece_size: u32) -> Vec<FileSpan> {
+        self.spans(piece_index as u64 * self.piece_length, piece_size)
+    }
+}
+
 pub mod hashes {
     use serde::de::{self, Deserialize, Deserializer, Visitor};
 	use serde::ser::{Serialize, Serializer};
@@ -121,7 +288,7 @@ pub mod hashes {
 		}
 	}
 
-    // note: this hash is actually not complete, because we also need to sort the keys in alphabetical order
+    // note: key ordering is handled by bencode::to_bytes, which re-encodes canonically
 	impl Serialize for Hashes {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
@@ -131,4 +298,103 @@ pub mod hashes {
             serializer.serialize_bytes(&single_slice)
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1 << 30;
+    const PIECE: u32 = 1 << 18;
+
+    /// Metadata only, the piece hashes are zeros
+    fn info(keys: Keys, num_pieces: usize) -> Info {
+        Info {
+            name: "big".to_string(),
+            plength: PIECE,
+            pieces: Hashes(vec![[0; 20]; num_pieces]),
+            keys,
+        }
+    }
+
+    fn multi_file(lengths: &[u64]) -> Keys {
+        Keys::MultiFile {
+            files: lengths
+                .iter()
+                .enumerate()
+                .map(|(i, &length)| File { length, path: vec![format!("file{i}")] })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn single_file_beyond_4_gib() {
+        let length = 5 * GIB + 1000;
+        let info = info(Keys::SingleFile { length }, 20481);
+        assert_eq!(info.length(), length);
+        assert_eq!(info.num_pieces(), 20481);
+        info.validate().unwrap();
+
+        // the first piece past 2^32
+        assert_eq!(info.piece_offset(16384), 1 << 32);
+        assert_eq!(info.piece_size(16384), PIECE);
+        assert_eq!(info.piece_offset(20480), 5 * GIB);
+        assert_eq!(info.piece_size(20480), 1000);
+    }
+
+    #[test]
+    fn single_file_of_exactly_4_gib_ends_on_a_full_piece() {
+        let info = info(Keys::SingleFile { length: 4 * GIB }, 16384);
+        info.validate().unwrap();
+        assert_eq!(info.piece_size(16383), PIECE);
+        assert_eq!(info.piece_offset(16383) + PIECE as u64, 1 << 32);
+    }
+
+    #[test]
+    fn validate_counts_pieces_beyond_4_gib() {
+        let length = 5 * GIB + 1000;
+        assert!(info(Keys::SingleFile { length }, 20480).validate().is_err());
+        assert!(info(Keys::SingleFile { length }, 20482).validate().is_err());
+        assert!(info(multi_file(&[(1 << 32) + 10, GIB]), 20480).validate().is_err());
+        info(multi_file(&[(1 << 32) + 10, GIB]), 20481).validate().unwrap();
+    }
+
+    #[test]
+    fn multi_file_piece_across_a_boundary_past_4_gib() {
+        let info = info(multi_file(&[(1 << 32) + 10, GIB]), 20481);
+        assert_eq!(info.length(), 5 * GIB + 10);
+        assert_eq!(info.piece_size(20480), 10);
+
+        let map = FileMap::new(&info);
+        assert_eq!(map.files[1].offset, (1 << 32) + 10);
+        let spans = map.piece_spans(16384, info.piece_size(16384));
+        assert_eq!(
+            spans,
+            vec![
+                FileSpan { file_index: 0, file_offset: 1 << 32, piece_offset: 0, len: 10 },
+                FileSpan { file_index: 1, file_offset: 0, piece_offset: 10, len: PIECE - 10 },
+            ]
+        );
+        // the last piece lies entirely in the second file
+        let spans = map.piece_spans(20480, info.piece_size(20480));
+        assert_eq!(spans, vec![FileSpan { file_index: 1, file_offset: GIB - 10, piece_offset: 0, len: 10 }]);
+    }
+
+    #[test]
+    fn info_hash_covers_keys_we_do_not_model() {
+        let info = [
+            &b"d6:lengthi5e4:name4:file12:piece lengthi16384e6:pieces20:"[..],
+            &[7; 20],
+            b"7:privatei1e6:source3:abce",
+        ]
+        .concat();
+        let file = [&b"d8:announce18:http://tracker/ann4:info"[..], &info, b"e"].concat();
+        let torrent = Torrent::from_bytes(&file).unwrap();
+        assert_eq!(torrent.info_hash(), <[u8; 20]>::from(Sha1::digest(&info)));
+
+        // the same info without the extra keys is another torrent
+        let rebuilt = Torrent::from_info(torrent.announce.clone(), torrent.info.clone()).unwrap();
+        assert_ne!(rebuilt.info_hash(), torrent.info_hash());
+        let modelled = [&b"d6:lengthi5e4:name4:file12:piece lengthi16384e6:pieces20:"[..], &[7; 20], b"e"].concat();
+        assert_eq!(rebuilt.info_hash(), <[u8; 20]>::from(Sha1::digest(&modelled)));
+    }
+}
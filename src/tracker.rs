@@ -1,8 +1,24 @@
 
 use anyhow::Context;
+use bytes::Bytes;
+use reqwest::header::{ACCEPT_ENCODING, CONTENT_ENCODING};
 use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::OnceLock;
+use std::time::Duration;
+use thiserror::Error;
 use peers::Peers;
-use crate::{torrent::{Keys, Torrent}, url_encode::url_encode};
+use crate::{gzip, torrent::Torrent, udp_tracker::UdpTracker, url_encode::url_encode};
+
+/// How long we wait for an HTTP tracker to accept the connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long a whole HTTP announce may take, redirects included
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// Most redirects we follow before giving up on a tracker
+const MAX_REDIRECTS: usize = 5;
+/// Most bytes a tracker response may have, before and after decompressing
+const MAX_RESPONSE_SIZE: usize = 16 << 20;
 
 
 #[derive(Debug, Clone, Serialize)]
@@ -18,59 +34,220 @@ pub struct TrackerRequest {
 	/// the port your client is listening on
 	pub port: u16,
 	/// the total amount uploaded so far
-	pub uploaded: usize,
+	pub uploaded: u64,
 	/// the total amount downloaded so far
-	pub downloaded: usize,
+	pub downloaded: u64,
 	/// the number of bytes left to download
-	pub left: u32,
+	pub left: u64,
 	/// whether the peer list should use the compact representation
 	/// The compact representation is more commonly used in the wild, the non-compact representation is mostly supported for backward-compatibility.
-	pub compact: u8
+	pub compact: u8,
+	/// Our public IPv4 address, so the tracker can hand it out even when we announce over IPv6 (BEP 7)
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub ipv4: Option<Ipv4Addr>,
+	/// Our public IPv6 address, so the tracker can hand it out even when we announce over IPv4 (BEP 7)
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub ipv6: Option<Ipv6Addr>,
+	/// why we announce, left out for the regular announces in between
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub event: Option<Event>,
+}
+
+impl TrackerRequest {
+	/// A regular announce before anything was transferred, with our public addresses if we have any
+	pub fn new(peer_id: String, port: u16, left: u64) -> Self {
+		TrackerRequest {
+			peer_id,
+			port,
+			uploaded: 0,
+			downloaded: 0,
+			left,
+			compact: 1,
+			ipv4: public_ip(([8, 8, 8, 8], 53).into()).and_then(|ip| match ip {
+				IpAddr::V4(ip) => Some(ip),
+				IpAddr::V6(_) => None,
+			}),
+			ipv6: public_ip((Ipv6Addr::new(0x2001, 0x4860, 0x4860, 0, 0, 0, 0, 0x8888), 53).into()).and_then(|ip| match ip {
+				IpAddr::V6(ip) => Some(ip),
+				IpAddr::V4(_) => None,
+			}),
+			event: None,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Event {
+	/// the first announce of a session
+	Started,
+	/// the download finished, not sent if we had everything from the start
+	Completed,
+	/// we are leaving the swarm
+	Stopped,
 }
 
 #[derive(Debug, Clone, Deserialize)]
 pub struct TrackerResponse {
+	/// If present, the tracker refused the announce and no other field may be present.
+	/// A human readable string explaining why.
+	#[serde(rename = "failure reason")]
+	pub failure_reason: Option<String>,
+	/// The announce went through, but the tracker has something to say about it
+	#[serde(rename = "warning message")]
+	pub warning_message: Option<String>,
 	/// An integer, indicating how often your client should make a request to the tracker in seconds
+	#[serde(default)]
 	pub interval: usize,
+	/// If present, don't announce more often than this many seconds, even when asking for more peers
+	#[serde(rename = "min interval")]
+	pub min_interval: Option<usize>,
+	/// If present, must be sent back as `trackerid` on the next announces
+	#[serde(rename = "tracker id")]
+	pub tracker_id: Option<ByteBuf>,
+	/// number of peers with the whole file
+	pub complete: Option<u64>,
+	/// number of peers still downloading
+	pub incomplete: Option<u64>,
+	/// Our IP address as the tracker sees it, 4 or 16 bytes (BEP 24). See `external_ip`.
+	#[serde(rename = "external ip")]
+	pub external_ip: Option<ByteBuf>,
 	/// A string, which contains list of peers that your client can connect to.
 	/// Each peer is represented using 6 bytes. The first 4 bytes are the peer's IP address and the last 2 bytes are the peer's port number.
-	pub peers: Peers
+	#[serde(default)]
+	pub peers: Peers,
+	/// The IPv6 peers, 18 bytes each: 16 for the address and 2 for the port (BEP 7)
+	#[serde(default, deserialize_with = "peers::compact6")]
+	pub peers6: Peers,
+
+}
+
+impl TrackerResponse {
+	/// The IPv4 and IPv6 peers together
+	pub fn into_peers(self) -> Peers {
+		let mut peers = self.peers;
+		peers.0.extend(self.peers6.0);
+		peers
+	}
 
+	/// Decodes the bencoded response of an HTTP tracker, a `failure reason` becomes `TrackerError::Failure`
+	pub fn from_bytes(bytes: &[u8]) -> Result<Self, TrackerError> {
+		let response: TrackerResponse = serde_bencode::from_bytes(bytes).context("parse tracker response")?;
+		if let Some(reason) = response.failure_reason {
+			return Err(TrackerError::Failure(reason));
+		}
+		Ok(response)
+	}
+
+	/// Our IP address as the tracker sees it, `None` if it didn't tell or it isn't 4 or 16 bytes
+	pub fn external_ip(&self) -> Option<IpAddr> {
+		let ip: &[u8] = self.external_ip.as_ref()?;
+		match <[u8; 4]>::try_from(ip) {
+			Ok(v4) => Some(IpAddr::from(v4)),
+			Err(_) => <[u8; 16]>::try_from(ip).ok().map(IpAddr::from),
+		}
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum TrackerError {
+	/// The tracker answered, but refused the announce
+	#[error("tracker refused the announce: {0}")]
+	Failure(String),
+	#[error(transparent)]
+	Other(anyhow::Error),
+}
+
+impl From<anyhow::Error> for TrackerError {
+	/// Keeps a `TrackerError` that was passed along as an `anyhow::Error` intact
+	fn from(error: anyhow::Error) -> Self {
+		error.downcast().unwrap_or_else(TrackerError::Other)
+	}
 }
 
 pub mod peers {
-    use serde::de::{self, Deserialize, Deserializer, Visitor};
-	use serde::ser::{Serialize, Serializer};
+    use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+	use serde::ser::{self, Serialize, Serializer};
+	use serde_bytes::ByteBuf;
     use std::fmt;
-    use std::net::{Ipv4Addr, SocketAddrV4};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
-	#[derive(Debug, Clone)]
-	pub struct Peers(pub Vec<SocketAddrV4>);
+	#[derive(Debug, Clone, Default)]
+	pub struct Peers(pub Vec<SocketAddr>);
 	struct PeersVisitor;
 
+	/// A peer in the dictionary model, which trackers use when we don't ask for the compact one.
+	/// Its `peer id` is left out, the handshake tells us that anyway.
+	#[derive(serde::Deserialize)]
+	struct DictPeer {
+		/// an IPv4 or IPv6 address, or a DNS name
+		ip: ByteBuf,
+		port: u16,
+	}
+
+	impl Peers {
+		/// Parses the compact representation, 6 bytes per peer, `None` if the length doesn't fit
+		pub fn from_compact(bytes: &[u8]) -> Option<Self> {
+			if bytes.len() % 6 != 0 {
+				return None;
+			}
+			Some(Peers(
+				bytes.chunks_exact(6)
+					.map(|slice_6| {SocketAddr::new(
+						Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]).into(),
+						u16::from_be_bytes([slice_6[4], slice_6[5]]),
+						)
+					})
+					.collect()
+			))
+		}
+
+		/// Parses the compact IPv6 representation of `peers6` (BEP 7), 18 bytes per peer,
+		/// `None` if the length doesn't fit
+		pub fn from_compact6(bytes: &[u8]) -> Option<Self> {
+			if bytes.len() % 18 != 0 {
+				return None;
+			}
+			Some(Peers(
+				bytes.chunks_exact(18)
+					.map(|slice_18| {
+						let ip: [u8; 16] = slice_18[..16].try_into().expect("16 bytes");
+						SocketAddr::new(Ipv6Addr::from(ip).into(), u16::from_be_bytes([slice_18[16], slice_18[17]]))
+					})
+					.collect()
+			))
+		}
+	}
+
 	impl<'de> Visitor<'de> for PeersVisitor {
 		type Value = Peers;
 
 		fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-			formatter.write_str("6 bytes. The first 4 are a peer's IP address and last 2 are a peer's port number")
+			formatter.write_str("6 bytes per peer, the first 4 are a peer's IP address and last 2 are a peer's port number, or a list of dictionaries with `ip` and `port`")
 		}
 
 		fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
 		where
 			E: de::Error,
 		{
-			if v.len() % 6 != 0 {
-				return Err(E::custom(format!("length is {}", v.len())));
+			Peers::from_compact(v).ok_or_else(|| E::custom(format!("length is {}", v.len())))
+		}
+
+		fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+		where
+			A: SeqAccess<'de>,
+		{
+			let mut peers = Vec::new();
+			while let Some(peer) = seq.next_element::<DictPeer>()? {
+				// peers given by DNS name are skipped, resolving them would hold up the whole response
+				let ip = std::str::from_utf8(&peer.ip)
+					.ok()
+					.and_then(|ip| ip.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>().ok());
+				if let Some(ip) = ip {
+					peers.push(SocketAddr::new(ip, peer.port));
+				}
 			}
-			Ok(Peers(
-				v.chunks_exact(6)
-					.map(|slice_6| {SocketAddrV4::new(
-						Ipv4Addr::new(slice_6[0], slice_6[1], slice_6[2], slice_6[3]),
-						u16::from_be_bytes([slice_6[4], slice_6[5]]),
-						)
-					})	
-					.collect()
-			))
+			Ok(Peers(peers))
 		}
 	}
 
@@ -79,10 +256,19 @@ pub mod peers {
 		where
 			D: Deserializer<'de>,
 		{
-			deserializer.deserialize_bytes(PeersVisitor)
+			deserializer.deserialize_any(PeersVisitor)
 		}
 	}
 
+	/// Deserializes the compact `peers6` string, for `#[serde(deserialize_with)]`
+	pub fn compact6<'de, D>(deserializer: D) -> Result<Peers, D::Error>
+	where
+		D: Deserializer<'de>,
+	{
+		let bytes = ByteBuf::deserialize(deserializer)?;
+		Peers::from_compact6(&bytes).ok_or_else(|| de::Error::custom(format!("peers6 length is {}", bytes.len())))
+	}
+
 	impl Serialize for Peers {
         fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
         where
@@ -90,6 +276,9 @@ pub mod peers {
         {
             let mut single_slice = Vec::with_capacity(6 * self.0.len());
 			for peer in &self.0 {
+				let SocketAddr::V4(peer) = peer else {
+					return Err(ser::Error::custom("IPv6 peers have no compact IPv4 representation"));
+				};
 				let ip = peer.ip().octets();
 				let port = peer.port().to_be_bytes();
 				single_slice.extend(&ip);
@@ -101,48 +290,203 @@ pub mod peers {
 }
 
 
+/// Announces us to the tracker of `torrent`, reachable on `port`, and returns the peers it knows
 pub async fn get_peers(
 	own_peer_id: String,
+	port: u16,
 	torrent: &Torrent,
-) -> Result<Peers, anyhow::Error> {
+) -> Result<Peers, TrackerError> {
 
-	let length = if let Keys::SingleFile { length } = torrent.info.keys {
-		length
-	} else {
-			todo!()
-	};
+	let request = TrackerRequest::new(own_peer_id, port, torrent.info.length());
 
-	let request = TrackerRequest {
-		peer_id: own_peer_id,
-		port: 6881,
-		uploaded: 0,
-		downloaded: 0,
-		left: length,
-		compact: 1
-	};
+	if torrent.announce.starts_with("udp://") {
+		let mut tracker = UdpTracker::new(&torrent.announce).await?;
+		let response = tracker.announce(torrent.info_hash(), &request).await?;
+		return Ok(response.peers);
+	}
+
+	let response = announce(&torrent.announce, torrent.info_hash(), &request, None).await?;
+	Ok(response.into_peers())
+}
+
+/// Announces to an HTTP tracker, `tracker_id` is what it handed out in an earlier response
+pub async fn announce(
+	announce_url: &str,
+	info_hash: [u8; 20],
+	request: &TrackerRequest,
+	tracker_id: Option<&[u8]>,
+) -> Result<TrackerResponse, TrackerError> {
 	let url_params =
-			serde_urlencoded::to_string(&request).context("url-encode tracker parameters")?;
+			serde_urlencoded::to_string(request).context("url-encode tracker parameters")?;
 
 	// put infohash here so that it wont get double url encoded
-	let tracker_url = format!(
+	let mut tracker_url = format!(
 			"{}?{}&info_hash={}",
-			torrent.announce,
+			announce_url,
 			url_params,
-			&url_encode(&torrent.info_hash())
+			&url_encode(&info_hash)
 	);
+	if let Some(tracker_id) = tracker_id {
+		tracker_url.push_str(&format!("&trackerid={}", url_encode(tracker_id)));
+	}
 
 	eprintln!("{tracker_url}");
-	let client = reqwest::blocking::Client::new();
-	let tracker_response = client
-			.get(tracker_url)
-			.send()
-			.context("send request to tracker")?
-			.bytes()
-			.context("convert response to bytes")?;
+	let tracker_response = http_get(&tracker_url).await?;
 
 	eprintln!("{:?}", tracker_response);
-	let response: TrackerResponse =
-		serde_bencode::from_bytes(&tracker_response).context("parse tracker response")?;
-	
-	Ok(response.peers)
-}
\ No newline at end of file
+	let response = TrackerResponse::from_bytes(&tracker_response)?;
+	if let Some(warning) = &response.warning_message {
+		eprintln!("Tracker warning: {warning}");
+	}
+	Ok(response)
+}
+
+/// The address we would reach `public_host` from, if it is one that other peers can reach too.
+/// Asks the routing table only, nothing is sent.
+fn public_ip(public_host: SocketAddr) -> Option<IpAddr> {
+	let socket = UdpSocket::bind(if public_host.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }).ok()?;
+	socket.connect(public_host).ok()?;
+	let ip = socket.local_addr().ok()?.ip();
+	let reachable = match ip {
+		IpAddr::V4(ip) => !(ip.is_private() || ip.is_loopback() || ip.is_link_local() || ip.is_unspecified()),
+		// unique local (fc00::/7) and link local (fe80::/10) addresses don't leave the site
+		IpAddr::V6(ip) => {
+			let prefix = ip.segments()[0];
+			!(ip.is_loopback() || ip.is_unspecified() || prefix & 0xfe00 == 0xfc00 || prefix & 0xffc0 == 0xfe80)
+		}
+	};
+	reachable.then_some(ip)
+}
+
+/// The client for all HTTP announces, so connections to a tracker are kept and reused between them
+fn http_client() -> anyhow::Result<&'static reqwest::Client> {
+	static CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+	if let Some(client) = CLIENT.get() {
+		return Ok(client);
+	}
+	let client = reqwest::Client::builder()
+		.connect_timeout(CONNECT_TIMEOUT)
+		.timeout(REQUEST_TIMEOUT)
+		.redirect(reqwest::redirect::Policy::limited(MAX_REDIRECTS))
+		.build()
+		.context("build http client")?;
+	Ok(CLIENT.get_or_init(|| client))
+}
+
+/// Fetches `url`, following redirects, and returns the body, decompressed if the tracker gzipped it
+async fn http_get(url: &str) -> anyhow::Result<Bytes> {
+	let mut response = http_client()?
+		.get(url)
+		.header(ACCEPT_ENCODING, "gzip")
+		.send()
+		.await
+		.context("send request to tracker")?;
+	let status = response.status();
+	let gzipped = response
+		.headers()
+		.get(CONTENT_ENCODING)
+		.is_some_and(|encoding| encoding.as_bytes().eq_ignore_ascii_case(b"gzip"));
+	if response.content_length().is_some_and(|len| len > MAX_RESPONSE_SIZE as u64) {
+		anyhow::bail!("tracker response is larger than {MAX_RESPONSE_SIZE} bytes");
+	}
+	// the length is only announced, or not at all when the body is chunked, so count what actually arrives
+	let mut body = Vec::new();
+	while let Some(chunk) = response.chunk().await.context("read tracker response")? {
+		anyhow::ensure!(
+			body.len() + chunk.len() <= MAX_RESPONSE_SIZE,
+			"tracker response is larger than {MAX_RESPONSE_SIZE} bytes"
+		);
+		body.extend_from_slice(&chunk);
+	}
+	let body = Bytes::from(body);
+	let body = if gzipped {
+		gzip::gunzip(&body, MAX_RESPONSE_SIZE).context("decompress tracker response")?.into()
+	} else {
+		body
+	};
+	// trackers often explain errors in a bencoded dictionary, so only give up on the status if there is none
+	anyhow::ensure!(status.is_success() || body.starts_with(b"d"), "tracker answered {status}");
+	Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn failure_reason_refuses_the_announce() {
+		match TrackerResponse::from_bytes(b"d14:failure reason17:unknown info hashe") {
+			Err(TrackerError::Failure(reason)) => assert_eq!(reason, "unknown info hash"),
+			other => panic!("expected a failure, got {other:?}"),
+		}
+		assert!(matches!(TrackerResponse::from_bytes(b"d8:intervali"), Err(TrackerError::Other(_))));
+	}
+
+	#[test]
+	fn decodes_the_optional_fields() {
+		let response = TrackerResponse::from_bytes(
+			b"d8:completei5e10:incompletei7e8:intervali1800e12:min intervali60e\
+			5:peers6:\x7f\x00\x00\x01\x1a\xe110:tracker id3:abc15:warning message9:slow downe",
+		)
+		.unwrap();
+		assert_eq!(response.warning_message.as_deref(), Some("slow down"));
+		assert_eq!(response.interval, 1800);
+		assert_eq!(response.min_interval, Some(60));
+		assert_eq!(response.tracker_id.as_deref().map(|id| &id[..]), Some(&b"abc"[..]));
+		assert_eq!((response.complete, response.incomplete), (Some(5), Some(7)));
+		assert_eq!(response.external_ip(), None);
+		assert_eq!(response.into_peers().0, vec!["127.0.0.1:6881".parse().unwrap()]);
+
+		// everything but the peers is optional
+		let response = TrackerResponse::from_bytes(b"d5:peers0:e").unwrap();
+		assert_eq!((response.interval, response.min_interval, response.complete), (0, None, None));
+		assert!(response.warning_message.is_none() && response.tracker_id.is_none());
+	}
+
+	#[test]
+	fn external_ip_is_4_or_16_bytes() {
+		let response = TrackerResponse::from_bytes(b"d11:external ip4:\x01\x02\x03\x045:peers0:e").unwrap();
+		assert_eq!(response.external_ip(), Some(IpAddr::from([1, 2, 3, 4])));
+
+		let mut bytes = b"d11:external ip16:".to_vec();
+		bytes.extend([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+		bytes.extend(b"5:peers0:e");
+		let response = TrackerResponse::from_bytes(&bytes).unwrap();
+		assert_eq!(response.external_ip(), Some("2001:db8::1".parse().unwrap()));
+
+		let response = TrackerResponse::from_bytes(b"d11:external ip5:\x01\x02\x03\x04\x055:peers0:e").unwrap();
+		assert_eq!(response.external_ip(), None);
+	}
+
+	#[test]
+	fn dictionary_peers_skip_dns_names() {
+		let response = TrackerResponse::from_bytes(
+			b"d5:peersl\
+			d2:ip8:10.0.0.17:peer id20:aaaaaaaaaaaaaaaaaaaa4:porti6881ee\
+			d2:ip13:[2001:db8::1]4:porti6882ee\
+			d2:ip7:fe80::24:porti6883ee\
+			d2:ip16:peer.example.org4:porti6884ee\
+			ee",
+		)
+		.unwrap();
+		let expected: Vec<SocketAddr> =
+			["10.0.0.1:6881", "[2001:db8::1]:6882", "[fe80::2]:6883"].iter().map(|addr| addr.parse().unwrap()).collect();
+		assert_eq!(response.into_peers().0, expected);
+	}
+
+	#[test]
+	fn peers6_must_be_18_bytes_per_peer() {
+		let mut bytes = b"d5:peers0:6:peers618:".to_vec();
+		bytes.extend([0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1a, 0xe1]);
+		bytes.push(b'e');
+		let response = TrackerResponse::from_bytes(&bytes).unwrap();
+		assert_eq!(response.into_peers().0, vec!["[2001:db8::1]:6881".parse::<SocketAddr>().unwrap()]);
+
+		let mut bytes = b"d5:peers0:6:peers617:".to_vec();
+		bytes.extend([0; 17]);
+		bytes.push(b'e');
+		let error = TrackerResponse::from_bytes(&bytes).unwrap_err();
+		assert!(matches!(error, TrackerError::Other(_)));
+		assert!(format!("{:#}", error).contains("peers6 length is 17"), "{error:#}");
+	}
+}
@@ -0,0 +1,223 @@
+use serde_bytes::ByteBuf;
+use std::time::Duration;
+use crate::download::{Download, PeerSource};
+use crate::torrent::Torrent;
+use crate::tracker::{self, peers::Peers, Event, TrackerError, TrackerRequest};
+use crate::udp_tracker::UdpTracker;
+
+/// How long we wait between announces until the tracker tells us
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+/// Announces one torrent to its tracker for as long as we take part in the swarm:
+/// `started` first, then again every interval the tracker asks for, `completed` once the last piece is verified
+/// and `stopped` when we leave. Every announce reports how much we transferred and have left so far.
+/// The peers the tracker returns on later announces join the download, which waits for them until `stop`.
+pub struct TrackerSession {
+    url: String,
+    info_hash: [u8; 20],
+    request: TrackerRequest,
+    /// kept across announces so the connection id is reused
+    udp: Option<UdpTracker>,
+    /// handed out by an HTTP tracker to be sent back on the following announces
+    tracker_id: Option<ByteBuf>,
+    interval: Duration,
+    /// whether the tracker knows we have the whole torrent, `completed` is sent at most once
+    complete: bool,
+    /// held from `start` to `stop`, the download keeps waiting for the peers we may still find
+    source: Option<PeerSource>,
+}
+
+impl TrackerSession {
+    /// A session with the tracker of `torrent`, we are reachable on `port`
+    pub fn new(torrent: &Torrent, peer_id: String, port: u16) -> Self {
+        TrackerSession {
+            url: torrent.announce.clone(),
+            info_hash: torrent.info_hash(),
+            request: TrackerRequest::new(peer_id, port, torrent.info.length()),
+            udp: None,
+            tracker_id: None,
+            interval: DEFAULT_INTERVAL,
+            complete: false,
+            source: None,
+        }
+    }
+
+    /// How long to wait before the next regular announce, as the tracker asked
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Sends `started` and returns the peers the tracker knows
+    pub async fn start(&mut self, download: &Download) -> Result<Peers, TrackerError> {
+        // a download that is complete from the start never sends `completed`
+        self.complete = download.remaining() == 0;
+        self.source = Some(download.peer_source());
+        self.announce(Some(Event::Started), download).await
+    }
+
+    /// Announces every interval, and right away with `completed` when the download finishes,
+    /// handing the peers the tracker returns to the download.
+    /// Runs until dropped, failed announces are tried again at the next interval.
+    pub async fn keep_announcing(&mut self, download: &Download) {
+        loop {
+            let event = tokio::select! {
+                _ = download.completed(), if !self.complete => Some(Event::Completed),
+                _ = tokio::time::sleep(self.interval) => None,
+            };
+            match self.announce(event, download).await {
+                Ok(peers) => {
+                    eprintln!("Tracker knows {} peers", peers.0.len());
+                    download.add_peers(&peers.0);
+                }
+                Err(e) => {
+                    eprintln!("Announce failed: {e}");
+                    // `completed` is due again right away, don't hammer the tracker with it
+                    if event.is_some() {
+                        tokio::time::sleep(self.interval).await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Sends `stopped`, after `completed` if the download finished since the last announce
+    pub async fn stop(&mut self, download: &Download) -> Result<(), TrackerError> {
+        self.source = None;
+        if !self.complete && download.remaining() == 0 {
+            self.announce(Some(Event::Completed), download).await?;
+        }
+        self.announce(Some(Event::Stopped), download).await?;
+        Ok(())
+    }
+
+    async fn announce(&mut self, event: Option<Event>, download: &Download) -> Result<Peers, TrackerError> {
+        self.request.event = event;
+        self.request.uploaded = download.uploaded();
+        self.request.downloaded = download.downloaded();
+        self.request.left = download.left();
+
+        let (interval, peers) = if self.url.starts_with("udp://") {
+            let tracker = match &mut self.udp {
+                Some(tracker) => tracker,
+                None => self.udp.insert(UdpTracker::new(&self.url).await?),
+            };
+            let response = tracker.announce(self.info_hash, &self.request).await?;
+            (response.interval as u64, response.peers)
+        } else {
+            let mut response =
+                tracker::announce(&self.url, self.info_hash, &self.request, self.tracker_id.as_deref().map(|id| &id[..])).await?;
+            if let Some(tracker_id) = response.tracker_id.take() {
+                self.tracker_id = Some(tracker_id);
+            }
+            (response.interval as u64, response.into_peers())
+        };
+        if interval > 0 {
+            self.interval = Duration::from_secs(interval);
+        }
+        if event == Some(Event::Completed) {
+            self.complete = true;
+        }
+        Ok(peers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::download::DownloadConfig;
+    use crate::listener::Listener;
+    use crate::storage::Storage;
+    use crate::torrent::{Hashes, Info, Keys};
+    use crate::udp_tracker::tests::{stand_in, StandIn};
+    use sha1::{Digest, Sha1};
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    const PIECE: u32 = 16 << 10;
+    const LENGTH: usize = 3 * PIECE as usize + 1000;
+
+    fn read_u64(packet: &[u8], offset: usize) -> u64 {
+        u64::from_be_bytes(packet[offset..offset + 8].try_into().unwrap())
+    }
+
+    /// The event, downloaded and left of every announce the stand-in received
+    fn announces(packets: &Mutex<Vec<Vec<u8>>>) -> Vec<(u32, u64, u64)> {
+        packets
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|packet| packet.len() >= 98 && packet[8..12] == 1u32.to_be_bytes())
+            .map(|packet| {
+                let event = u32::from_be_bytes(packet[80..84].try_into().unwrap());
+                (event, read_u64(packet, 56), read_u64(packet, 64))
+            })
+            .collect()
+    }
+
+    fn download(info: &Info, dir: &tempfile::TempDir, config: DownloadConfig) -> Download {
+        let storage = Storage::create(&dir.path().join("fake"), info).unwrap();
+        Download::new([1; 20], [2; 20], info, Arc::new(storage), None, config)
+    }
+
+    #[tokio::test]
+    async fn announces_started_completed_once_and_stopped_with_live_counts() {
+        let data: Vec<u8> = (0..LENGTH).map(|i| (i * 7 % 251) as u8).collect();
+        let info = Info {
+            name: "fake".to_string(),
+            plength: PIECE,
+            pieces: Hashes(data.chunks(PIECE as usize).map(|piece| Sha1::digest(piece).into()).collect()),
+            keys: Keys::SingleFile { length: LENGTH as u64 },
+        };
+        let (url, packets) = stand_in(StandIn::default()).await;
+        let torrent = Torrent::from_info(url, info.clone()).unwrap();
+
+        // a seeder behind a listener for the download to get the data from
+        let seeder_dir = tempfile::tempdir().unwrap();
+        std::fs::write(seeder_dir.path().join("fake"), &data).unwrap();
+        let seeder = download(&info, &seeder_dir, DownloadConfig { seed: true, ..DownloadConfig::default() });
+        seeder.mark_have(&crate::bitfield::Bitfield::full(info.num_pieces()));
+        let listener = Arc::new(Listener::bind(0).await.unwrap());
+        listener.add(&seeder);
+        let seeder_addr = SocketAddr::from(([127, 0, 0, 1], listener.port()));
+        tokio::spawn({
+            let listener = listener.clone();
+            async move { listener.run().await }
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let download = download(&info, &dir, DownloadConfig::default());
+        let mut session = TrackerSession::new(&torrent, "-RS0001-000000000000".to_string(), 6881);
+        assert_eq!(session.interval(), DEFAULT_INTERVAL);
+        let peers = session.start(&download).await.unwrap();
+        assert_eq!(peers.0.len(), 2);
+        assert_eq!(session.interval(), Duration::from_secs(1800));
+        assert_eq!(announces(&packets), vec![(2, 0, LENGTH as u64)]);
+
+        tokio::time::timeout(Duration::from_secs(30), download.run(&[seeder_addr]))
+            .await
+            .expect("download timed out")
+            .expect("download failed");
+        // the finished download is announced right away, not after the interval
+        tokio::time::timeout(Duration::from_secs(5), async {
+            tokio::select! {
+                _ = session.keep_announcing(&download) => unreachable!("keeps announcing until dropped"),
+                _ = async {
+                    while announces(&packets).len() < 2 {
+                        tokio::time::sleep(Duration::from_millis(10)).await;
+                    }
+                    // let the session take in the answer
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                } => {}
+            }
+        })
+        .await
+        .expect("completed was not announced");
+
+        session.stop(&download).await.unwrap();
+        assert_eq!(
+            announces(&packets),
+            vec![(2, 0, LENGTH as u64), (1, LENGTH as u64, 0), (3, LENGTH as u64, 0)],
+        );
+    }
+}
+
@@ -0,0 +1,101 @@
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+
+/// Score change for every piece that passed the hash check with data from a peer
+const PASSED_PIECE_SCORE: i32 = 1;
+/// Score change for every piece that failed the hash check with data from a peer
+const FAILED_PIECE_PENALTY: i32 = 5;
+/// Peers whose score drops to this are banned
+const BAN_SCORE: i32 = -10;
+
+/// How much we trust the data of each peer, for the rest of the session.
+/// Pieces that pass the hash check raise the score of the peers that sent them, failed pieces lower it,
+/// and peers whose score drops too low or that are caught sending a corrupt block are banned.
+/// A ban covers the peer's whole IP address, so it can't come back from another port.
+#[derive(Debug, Clone, Default)]
+pub struct PeerTrust {
+    scores: HashMap<SocketAddr, i32>,
+    strikes: HashMap<SocketAddr, u32>,
+    banned: HashSet<SocketAddr>,
+    banned_ips: HashSet<IpAddr>,
+}
+
+impl PeerTrust {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn score(&self, peer: SocketAddr) -> i32 {
+        self.scores.get(&peer).copied().unwrap_or(0)
+    }
+
+    /// Number of pieces that failed the hash check with data from `peer`
+    pub fn strikes(&self, peer: SocketAddr) -> u32 {
+        self.strikes.get(&peer).copied().unwrap_or(0)
+    }
+
+    /// Whether `peer` or another peer on the same IP address was banned
+    pub fn is_banned(&self, peer: SocketAddr) -> bool {
+        self.banned_ips.contains(&peer.ip())
+    }
+
+    pub fn banned(&self) -> Vec<SocketAddr> {
+        let mut banned: Vec<_> = self.banned.iter().copied().collect();
+        banned.sort();
+        banned
+    }
+
+    pub fn ban(&mut self, peer: SocketAddr) {
+        self.banned.insert(peer);
+        self.banned_ips.insert(peer.ip());
+    }
+
+    pub fn piece_passed(&mut self, contributors: &HashSet<SocketAddr>) {
+        for &peer in contributors {
+            *self.scores.entry(peer).or_default() += PASSED_PIECE_SCORE;
+        }
+    }
+
+    /// Penalizes every peer that sent data for a failed piece, returns the peers that got banned for it
+    pub fn piece_failed(&mut self, contributors: &HashSet<SocketAddr>) -> Vec<SocketAddr> {
+        let mut newly_banned = Vec::new();
+        for &peer in contributors {
+            *self.strikes.entry(peer).or_default() += 1;
+            let score = self.scores.entry(peer).or_default();
+            *score -= FAILED_PIECE_PENALTY;
+            if *score <= BAN_SCORE && self.banned.insert(peer) {
+                self.banned_ips.insert(peer.ip());
+                newly_banned.push(peer);
+            }
+        }
+        newly_banned
+    }
+
+    /// Takes back the penalty of a failed piece once it turned out `peer`'s blocks in it were fine
+    pub fn forgive(&mut self, peer: SocketAddr) {
+        *self.scores.entry(peer).or_default() += FAILED_PIECE_PENALTY;
+        if let Some(strikes) = self.strikes.get_mut(&peer) {
+            *strikes = strikes.saturating_sub(1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_ban_covers_every_port_of_the_address() {
+        let mut trust = PeerTrust::new();
+        let peer = SocketAddr::from(([10, 0, 0, 1], 50000));
+        trust.ban(peer);
+        assert!(trust.is_banned(SocketAddr::from(([10, 0, 0, 1], 50001))));
+        assert!(!trust.is_banned(SocketAddr::from(([10, 0, 0, 2], 50000))));
+
+        let mut trust = PeerTrust::new();
+        let contributors = HashSet::from([peer]);
+        while trust.piece_failed(&contributors).is_empty() {}
+        assert!(trust.is_banned(SocketAddr::from(([10, 0, 0, 1], 6881))));
+        assert_eq!(trust.banned(), vec![peer]);
+    }
+}
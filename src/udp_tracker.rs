@@ -0,0 +1,406 @@
+use anyhow::Context;
+use std::time::Duration;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::Instant;
+use crate::tracker::{peers::Peers, Event, TrackerError, TrackerRequest};
+
+/// Magic constant that starts every connect request
+const PROTOCOL_ID: u64 = 0x41727101980;
+
+const ACTION_CONNECT: u32 = 0;
+const ACTION_ANNOUNCE: u32 = 1;
+const ACTION_SCRAPE: u32 = 2;
+const ACTION_ERROR: u32 = 3;
+
+/// How long a connection id handed out by the tracker may be used
+const CONNECTION_ID_LIFETIME: Duration = Duration::from_secs(60);
+
+/// Most info hashes one scrape request can carry
+const MAX_SCRAPE_HASHES: usize = 74;
+
+/// How long we wait for the first answer by default, doubled for every retry.
+/// BEP 15 suggests 15 seconds and 8 retries, which leaves a user waiting for hours on a dead tracker.
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
+const DEFAULT_MAX_RETRIES: u32 = 2;
+
+/// Big enough for any UDP datagram, so no peers of a large announce response get cut off
+const MAX_PACKET_SIZE: usize = 65536;
+
+/// The answer to an announce
+#[derive(Debug, Clone)]
+pub struct UdpAnnounce {
+    /// seconds to wait before announcing again
+    pub interval: u32,
+    pub leechers: u32,
+    pub seeders: u32,
+    pub peers: Peers,
+}
+
+/// What a tracker knows about one torrent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ScrapeStats {
+    pub seeders: u32,
+    /// number of times the torrent was downloaded completely
+    pub completed: u32,
+    pub leechers: u32,
+}
+
+/// A client for a `udp://host:port` tracker (BEP 15).
+/// Every request first needs a connection id, which is kept for a minute.
+/// Requests carry a random transaction id and are sent again with doubling timeouts while the tracker doesn't answer.
+#[derive(Debug)]
+pub struct UdpTracker {
+    socket: UdpSocket,
+    connection: Option<(u64, Instant)>,
+    /// identifies us to the tracker across announces, even if our IP address changes
+    key: u32,
+    timeout: Duration,
+    max_retries: u32,
+}
+
+impl UdpTracker {
+    /// Resolves the tracker of an announce url like `udp://tracker.example.org:6969/announce`
+    pub async fn new(url: &str) -> anyhow::Result<Self> {
+        let host = url.strip_prefix("udp://").context("not a udp:// tracker url")?;
+        let host = host.split('/').next().unwrap_or(host);
+        let addr = lookup_host(host)
+            .await
+            .with_context(|| format!("resolve tracker {host}"))?
+            .next()
+            .with_context(|| format!("tracker {host} has no address"))?;
+        let socket = UdpSocket::bind(if addr.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" })
+            .await
+            .context("bind udp socket")?;
+        socket.connect(addr).await.context("connect udp socket")?;
+        Ok(UdpTracker {
+            socket,
+            connection: None,
+            key: rand::random(),
+            timeout: DEFAULT_TIMEOUT,
+            max_retries: DEFAULT_MAX_RETRIES,
+        })
+    }
+
+    /// Changes how long we wait for the first answer (3 seconds) and how often the wait is doubled
+    /// and the request sent again (2 times) before giving up, e.g. to BEP 15's 15 seconds and 8 times
+    pub fn set_timeout(&mut self, timeout: Duration, max_retries: u32) {
+        self.timeout = timeout;
+        self.max_retries = max_retries;
+    }
+
+    pub async fn announce(&mut self, info_hash: [u8; 20], request: &TrackerRequest) -> anyhow::Result<UdpAnnounce> {
+        let peer_id: [u8; 20] = request
+            .peer_id
+            .as_bytes()
+            .try_into()
+            .context("peer id must be 20 bytes")?;
+        let mut payload = Vec::with_capacity(82);
+        payload.extend_from_slice(&info_hash);
+        payload.extend_from_slice(&peer_id);
+        payload.extend_from_slice(&request.downloaded.to_be_bytes());
+        payload.extend_from_slice(&request.left.to_be_bytes());
+        payload.extend_from_slice(&request.uploaded.to_be_bytes());
+        let event: u32 = match request.event {
+            None => 0,
+            Some(Event::Completed) => 1,
+            Some(Event::Started) => 2,
+            Some(Event::Stopped) => 3,
+        };
+        payload.extend_from_slice(&event.to_be_bytes());
+        payload.extend_from_slice(&0u32.to_be_bytes()); // ip: the one the request came from
+        payload.extend_from_slice(&self.key.to_be_bytes());
+        payload.extend_from_slice(&(-1i32).to_be_bytes()); // num want: the tracker's default
+        payload.extend_from_slice(&request.port.to_be_bytes());
+
+        let response = self.request(ACTION_ANNOUNCE, &payload).await?;
+        anyhow::ensure!(response.len() >= 12, "announce response is too short");
+        Ok(UdpAnnounce {
+            interval: read_u32(&response, 0),
+            leechers: read_u32(&response, 4),
+            seeders: read_u32(&response, 8),
+            // the tracker answers with peers of the address family we reached it over
+            peers: if self.socket.peer_addr().context("udp socket address")?.is_ipv6() {
+                Peers::from_compact6(&response[12..])
+            } else {
+                Peers::from_compact(&response[12..])
+            }
+            .context("announce response has a partial peer")?,
+        })
+    }
+
+    /// Asks for the number of seeders, leechers and completed downloads of each torrent
+    pub async fn scrape(&mut self, info_hashes: &[[u8; 20]]) -> anyhow::Result<Vec<ScrapeStats>> {
+        anyhow::ensure!(
+            info_hashes.len() <= MAX_SCRAPE_HASHES,
+            "can't scrape more than {MAX_SCRAPE_HASHES} torrents at once"
+        );
+        let payload = info_hashes.concat();
+        let response = self.request(ACTION_SCRAPE, &payload).await?;
+        anyhow::ensure!(response.len() >= 12 * info_hashes.len(), "scrape response is too short");
+        Ok(response
+            .chunks_exact(12)
+            .take(info_hashes.len())
+            .map(|stats| ScrapeStats {
+                seeders: read_u32(stats, 0),
+                completed: read_u32(stats, 4),
+                leechers: read_u32(stats, 8),
+            })
+            .collect())
+    }
+
+    async fn connection_id(&mut self) -> anyhow::Result<u64> {
+        if let Some((id, since)) = self.connection {
+            if since.elapsed() < CONNECTION_ID_LIFETIME {
+                return Ok(id);
+            }
+        }
+        for attempt in 0..=self.max_retries {
+            let Some(response) = self.attempt(PROTOCOL_ID, ACTION_CONNECT, &[], attempt).await? else {
+                continue;
+            };
+            anyhow::ensure!(response.len() >= 8, "connect response is too short");
+            let id = u64::from_be_bytes(response[..8].try_into().expect("8 bytes"));
+            self.connection = Some((id, Instant::now()));
+            return Ok(id);
+        }
+        anyhow::bail!("tracker did not answer the connect request");
+    }
+
+    /// Sends a request until it is answered, connecting again when the connection id expires in between
+    async fn request(&mut self, action: u32, payload: &[u8]) -> anyhow::Result<Vec<u8>> {
+        for attempt in 0..=self.max_retries {
+            let connection_id = self.connection_id().await?;
+            if let Some(response) = self.attempt(connection_id, action, payload, attempt).await? {
+                return Ok(response);
+            }
+        }
+        anyhow::bail!("tracker did not answer after {} attempts", self.max_retries + 1);
+    }
+
+    /// Sends a request once and waits `timeout * 2^attempt` for the answer.
+    /// Returns the answer after its action and transaction id, `None` if it didn't come in time.
+    async fn attempt(&self, connection_id: u64, action: u32, payload: &[u8], attempt: u32) -> anyhow::Result<Option<Vec<u8>>> {
+        let transaction_id: u32 = rand::random();
+        let mut packet = Vec::with_capacity(16 + payload.len());
+        packet.extend_from_slice(&connection_id.to_be_bytes());
+        packet.extend_from_slice(&action.to_be_bytes());
+        packet.extend_from_slice(&transaction_id.to_be_bytes());
+        packet.extend_from_slice(payload);
+        self.socket.send(&packet).await.context("send to tracker")?;
+
+        let deadline = Instant::now() + self.timeout.saturating_mul(1 << attempt.min(8));
+        let mut buf = vec![0; MAX_PACKET_SIZE];
+        loop {
+            let Ok(received) = tokio::time::timeout_at(deadline, self.socket.recv(&mut buf)).await else {
+                return Ok(None);
+            };
+            let len = received.context("receive from tracker")?;
+            // late answers to earlier attempts and garbage are skipped
+            if len < 8 || read_u32(&buf, 4) != transaction_id {
+                continue;
+            }
+            let body = buf[8..len].to_vec();
+            match read_u32(&buf, 0) {
+                ACTION_ERROR => return Err(TrackerError::Failure(String::from_utf8_lossy(&body).into_owned()).into()),
+                answered if answered != action => {
+                    anyhow::bail!("tracker answered action {answered} to a request for action {action}")
+                }
+                _ => return Ok(Some(body)),
+            }
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_be_bytes(bytes[at..at + 4].try_into().expect("4 bytes"))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::tracker::Event;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+
+    const CONNECTION_ID: u64 = 0x1234_5678;
+
+    /// How the stand-in tracker misbehaves
+    #[derive(Clone, Default)]
+    pub(crate) struct StandIn {
+        /// packets to ignore before answering
+        drop_first: usize,
+        /// answer every request with a wrong transaction id first
+        wrong_transaction_first: bool,
+        /// answer announces and scrapes with this error
+        error: Option<&'static str>,
+    }
+
+    /// Starts a stand-in tracker, returns its url and every packet it received
+    pub(crate) async fn stand_in(behaviour: StandIn) -> (String, Arc<Mutex<Vec<Vec<u8>>>>) {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("udp://{}/announce", socket.local_addr().unwrap());
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let log = received.clone();
+        tokio::spawn(async move {
+            let mut buf = vec![0; MAX_PACKET_SIZE];
+            loop {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                let packet = buf[..len].to_vec();
+                let count = {
+                    let mut log = log.lock().unwrap();
+                    log.push(packet.clone());
+                    log.len()
+                };
+                if count <= behaviour.drop_first {
+                    continue;
+                }
+                let action = read_u32(&packet, 8);
+                let transaction_id = read_u32(&packet, 12);
+                let mut body = Vec::new();
+                let answer_action = match (action, behaviour.error) {
+                    (ACTION_CONNECT, _) => {
+                        body.extend_from_slice(&CONNECTION_ID.to_be_bytes());
+                        ACTION_CONNECT
+                    }
+                    (_, Some(error)) => {
+                        body.extend_from_slice(error.as_bytes());
+                        ACTION_ERROR
+                    }
+                    (ACTION_ANNOUNCE, None) => {
+                        for value in [1800u32, 2, 3] {
+                            body.extend_from_slice(&value.to_be_bytes());
+                        }
+                        body.extend_from_slice(&[10, 0, 0, 1, 0x1a, 0xe1, 10, 0, 0, 2, 0x1a, 0xe2]);
+                        ACTION_ANNOUNCE
+                    }
+                    _ => {
+                        // seeders i, completed i + 1, leechers i + 2 for the i-th hash
+                        for i in 0..((len - 16) / 20) as u32 {
+                            for value in [i, i + 1, i + 2] {
+                                body.extend_from_slice(&value.to_be_bytes());
+                            }
+                        }
+                        ACTION_SCRAPE
+                    }
+                };
+                let reply = |transaction_id: u32| {
+                    let mut reply = answer_action.to_be_bytes().to_vec();
+                    reply.extend_from_slice(&transaction_id.to_be_bytes());
+                    reply.extend_from_slice(&body);
+                    reply
+                };
+                if behaviour.wrong_transaction_first {
+                    socket.send_to(&reply(transaction_id.wrapping_add(1)), from).await.unwrap();
+                }
+                socket.send_to(&reply(transaction_id), from).await.unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    fn request(event: Option<Event>) -> TrackerRequest {
+        TrackerRequest {
+            event,
+            ..TrackerRequest::new("00112233445566778899".to_string(), 6881, 1000)
+        }
+    }
+
+    fn action(packet: &[u8]) -> u32 {
+        read_u32(packet, 8)
+    }
+
+    #[tokio::test]
+    async fn connects_then_announces() {
+        let (url, received) = stand_in(StandIn::default()).await;
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+        let announce = tracker.announce([7; 20], &request(Some(Event::Started))).await.unwrap();
+        assert_eq!((announce.interval, announce.leechers, announce.seeders), (1800, 2, 3));
+        assert_eq!(
+            announce.peers.0,
+            vec![SocketAddr::from(([10, 0, 0, 1], 6881)), SocketAddr::from(([10, 0, 0, 2], 6882))]
+        );
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 2);
+        assert_eq!(u64::from_be_bytes(received[0][..8].try_into().unwrap()), PROTOCOL_ID);
+        assert_eq!(action(&received[0]), ACTION_CONNECT);
+        let announce = &received[1];
+        assert_eq!(u64::from_be_bytes(announce[..8].try_into().unwrap()), CONNECTION_ID);
+        assert_eq!(action(announce), ACTION_ANNOUNCE);
+        assert_eq!(&announce[16..36], &[7; 20]);
+        // left, then the event: started is 2
+        assert_eq!(u64::from_be_bytes(announce[64..72].try_into().unwrap()), 1000);
+        assert_eq!(read_u32(announce, 80), 2);
+    }
+
+    #[tokio::test]
+    async fn reuses_the_connection_id_until_it_expires() {
+        let (url, received) = stand_in(StandIn::default()).await;
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+        tracker.announce([7; 20], &request(None)).await.unwrap();
+        tracker.announce([7; 20], &request(None)).await.unwrap();
+        let actions: Vec<u32> = received.lock().unwrap().iter().map(|packet| action(packet)).collect();
+        assert_eq!(actions, vec![ACTION_CONNECT, ACTION_ANNOUNCE, ACTION_ANNOUNCE]);
+
+        let (id, since) = tracker.connection.unwrap();
+        tracker.connection = Some((id, since - CONNECTION_ID_LIFETIME));
+        tracker.announce([7; 20], &request(None)).await.unwrap();
+        let actions: Vec<u32> = received.lock().unwrap().iter().map(|packet| action(packet)).collect();
+        assert_eq!(actions[3..], [ACTION_CONNECT, ACTION_ANNOUNCE]);
+    }
+
+    #[tokio::test]
+    async fn skips_answers_with_another_transaction_id() {
+        let (url, _) = stand_in(StandIn { wrong_transaction_first: true, ..StandIn::default() }).await;
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+        let announce = tracker.announce([7; 20], &request(None)).await.unwrap();
+        assert_eq!(announce.interval, 1800);
+    }
+
+    #[tokio::test]
+    async fn error_action_is_a_tracker_failure() {
+        let (url, _) = stand_in(StandIn { error: Some("torrent not registered"), ..StandIn::default() }).await;
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+        let error = tracker.announce([7; 20], &request(None)).await.unwrap_err();
+        match TrackerError::from(error) {
+            TrackerError::Failure(reason) => assert_eq!(reason, "torrent not registered"),
+            other => panic!("expected a failure, got {other}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn sends_again_when_the_tracker_does_not_answer() {
+        let (url, received) = stand_in(StandIn { drop_first: 1, ..StandIn::default() }).await;
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+        tracker.set_timeout(Duration::from_millis(50), 2);
+        tracker.announce([7; 20], &request(None)).await.unwrap();
+        let actions: Vec<u32> = received.lock().unwrap().iter().map(|packet| action(packet)).collect();
+        assert_eq!(actions, vec![ACTION_CONNECT, ACTION_CONNECT, ACTION_ANNOUNCE]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_on_a_dead_tracker() {
+        let (url, received) = stand_in(StandIn { drop_first: usize::MAX, ..StandIn::default() }).await;
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+        tracker.set_timeout(Duration::from_millis(20), 2);
+        assert!(tracker.announce([7; 20], &request(None)).await.is_err());
+        // 20, 40 and 80 ms
+        assert_eq!(received.lock().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn scrapes_several_torrents() {
+        let (url, received) = stand_in(StandIn::default()).await;
+        let mut tracker = UdpTracker::new(&url).await.unwrap();
+        let stats = tracker.scrape(&[[1; 20], [2; 20]]).await.unwrap();
+        assert_eq!(
+            stats,
+            vec![
+                ScrapeStats { seeders: 0, completed: 1, leechers: 2 },
+                ScrapeStats { seeders: 1, completed: 2, leechers: 3 },
+            ]
+        );
+        assert_eq!(&received.lock().unwrap()[1][16..], [[1; 20], [2; 20]].concat());
+        assert!(tracker.scrape(&vec![[0; 20]; MAX_SCRAPE_HASHES + 1]).await.is_err());
+    }
+}
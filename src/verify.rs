@@ -0,0 +1,188 @@
+use sha1::{Digest, Sha1};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use crate::bitfield::Bitfield;
+use crate::storage::Storage;
+use crate::torrent::Info;
+
+/// How much of one file is covered by pieces that passed the hash check
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileProgress {
+    pub path: PathBuf,
+    pub length: u64,
+    pub verified: u64,
+}
+
+impl FileProgress {
+    pub fn percent(&self) -> f64 {
+        if self.length == 0 {
+            100.0
+        } else {
+            self.verified as f64 * 100.0 / self.length as f64
+        }
+    }
+}
+
+/// Result of hash checking data on disk against a torrent
+#[derive(Debug, Clone)]
+pub struct VerifyReport {
+    /// pieces whose data matches the hash in the torrent
+    pub valid: Bitfield,
+    pub num_pieces: usize,
+    pub files: Vec<FileProgress>,
+}
+
+impl VerifyReport {
+    pub fn failed(&self) -> impl Iterator<Item = u32> + '_ {
+        (0..self.num_pieces as u32).filter(|&index| !self.valid.has(index))
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.valid.count() == self.num_pieces
+    }
+}
+
+/// Reads and hashes one piece, missing or short files count as a mismatch
+pub fn check_piece(storage: &Storage, info: &Info, piece_index: u32) -> bool {
+    let Some(expected) = info.pieces.0.get(piece_index as usize) else {
+        return false;
+    };
+    storage
+        .read_piece(piece_index)
+        .is_ok_and(|piece| <[u8; 20]>::from(Sha1::digest(piece)) == *expected)
+}
+
+/// Hashes every piece of the torrent as found in `storage`.
+/// With `threads` > 1 the pieces are spread over that many threads, 0 uses one thread per core.
+/// This blocks, call it through `tokio::task::spawn_blocking` from async code.
+pub fn verify(storage: &Storage, info: &Info, threads: usize) -> VerifyReport {
+    let num_pieces = info.num_pieces();
+    let threads = match threads {
+        0 => std::thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    };
+    let valid = Mutex::new(Bitfield::new(num_pieces));
+    let next = AtomicU32::new(0);
+    let check = || loop {
+        let index = next.fetch_add(1, Ordering::Relaxed);
+        if index as usize >= num_pieces {
+            return;
+        }
+        if check_piece(storage, info, index) {
+            valid.lock().expect("verify lock poisoned").set(index);
+        }
+    };
+    std::thread::scope(|scope| {
+        for _ in 1..threads.min(num_pieces) {
+            scope.spawn(check);
+        }
+        check();
+    });
+    let valid = valid.into_inner().expect("verify lock poisoned");
+
+    let file_map = storage.file_map();
+    let mut files: Vec<FileProgress> = file_map
+        .files
+        .iter()
+        .zip(storage.file_paths())
+        .map(|(file, path)| FileProgress {
+            path: path.clone(),
+            length: file.length,
+            verified: 0,
+        })
+        .collect();
+    for index in valid.pieces() {
+        for span in file_map.piece_spans(index, info.piece_size(index)) {
+            files[span.file_index].verified += span.len as u64;
+        }
+    }
+
+    VerifyReport {
+        valid,
+        num_pieces,
+        files,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::torrent::{File, Hashes, Keys};
+
+    const PIECE: u32 = 16;
+
+    fn info(data: &[u8], keys: Keys) -> Info {
+        Info {
+            name: "out".to_string(),
+            plength: PIECE,
+            pieces: Hashes(data.chunks(PIECE as usize).map(|piece| Sha1::digest(piece).into()).collect()),
+            keys,
+        }
+    }
+
+    fn data(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 13 % 256) as u8).collect()
+    }
+
+    /// Checks with one and with several threads, which must agree
+    fn verify_all(storage: &Storage, info: &Info) -> VerifyReport {
+        let report = verify(storage, info, 1);
+        let parallel = verify(storage, info, 4);
+        assert_eq!((&parallel.valid, &parallel.files), (&report.valid, &report.files));
+        report
+    }
+
+    #[test]
+    fn single_file_with_a_corrupt_piece() {
+        let mut data = data(40);
+        let info = info(&data, Keys::SingleFile { length: 40 });
+        data[20] ^= 1;
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        std::fs::write(&output, &data).unwrap();
+
+        let report = verify_all(&Storage::open(&output, &info).unwrap(), &info);
+        assert_eq!(report.failed().collect::<Vec<_>>(), vec![1]);
+        assert!(!report.is_complete());
+        assert_eq!(report.files.len(), 1);
+        assert_eq!(report.files[0].percent(), 60.0);
+    }
+
+    #[test]
+    fn multi_file_with_a_corrupt_piece_and_a_missing_file() {
+        // piece 0 lies in file 0, piece 1 spans files 0 and 1, piece 2 lies in file 2
+        let mut data = data(48);
+        let files = [20, 12, 16]
+            .into_iter()
+            .enumerate()
+            .map(|(i, length)| File { length, path: vec![format!("file{i}")] })
+            .collect();
+        let info = info(&data, Keys::MultiFile { files });
+        data[3] ^= 1;
+        let dir = tempfile::tempdir().unwrap();
+        let paths = Storage::paths(dir.path(), &info);
+        std::fs::create_dir_all(paths[0].parent().unwrap()).unwrap();
+        std::fs::write(&paths[0], &data[..20]).unwrap();
+        std::fs::write(&paths[1], &data[20..32]).unwrap();
+
+        let report = verify_all(&Storage::open(dir.path(), &info).unwrap(), &info);
+        assert_eq!(report.failed().collect::<Vec<_>>(), vec![0, 2]);
+        let percents: Vec<f64> = report.files.iter().map(FileProgress::percent).collect();
+        assert_eq!(percents, vec![20.0, 100.0, 0.0]);
+        assert_eq!(report.files[2].path, paths[2]);
+    }
+
+    #[test]
+    fn complete_data_passes() {
+        let data = data(40);
+        let info = info(&data, Keys::SingleFile { length: 40 });
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        std::fs::write(&output, &data).unwrap();
+        let report = verify_all(&Storage::open(&output, &info).unwrap(), &info);
+        assert!(report.is_complete());
+        assert_eq!(report.failed().count(), 0);
+        assert_eq!(report.files[0].percent(), 100.0);
+    }
+}